@@ -0,0 +1,112 @@
+//! Hammer `GET /fish` with concurrent requests from many sessions, and report
+//! throughput and latency. Run against a local server:
+//!
+//! ```sh
+//! cargo run --release &
+//! cargo run --release --example load_list_fish
+//! ```
+//!
+//! Configured with environment variables:
+//! - `HOST`: Server address (default `127.0.0.1:3000`)
+//! - `CONCURRENCY`: Number of concurrent clients, each with its own session
+//!   (default `32`)
+//! - `REQUESTS`: Number of requests per client (default `200`)
+
+use std::{
+    env,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+#[tokio::main]
+async fn main() {
+    let host = env::var("HOST").unwrap_or_else(|_| "127.0.0.1:3000".into());
+    let concurrency = env_number("CONCURRENCY", 32);
+    let requests = env_number("REQUESTS", 200);
+
+    println!(
+        "Sending {concurrency} x {requests} requests to \
+        http://{host}/fish"
+    );
+    let start = Instant::now();
+    let clients: Vec<_> = (0..concurrency)
+        .map(|_| tokio::spawn(client(host.clone(), requests)))
+        .collect();
+    let mut latencies = Vec::with_capacity(concurrency * requests);
+    for client in clients {
+        latencies.extend(client.await.expect("Client panicked"));
+    }
+    let elapsed = start.elapsed();
+
+    latencies.sort();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    println!(
+        "{} requests in {elapsed:.2?} ({:.0} req/s)",
+        latencies.len(),
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "Latency: p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, max {:.2?}",
+        percentile(50),
+        percentile(90),
+        percentile(99),
+        percentile(100),
+    );
+}
+
+/// Log in, then list fish repeatedly. Return the latency of each request
+async fn client(host: String, requests: usize) -> Vec<Duration> {
+    let login = send(&host, "POST", "/login", None).await;
+    let login: serde_json::Value =
+        serde_json::from_str(&login).expect("Invalid login response");
+    let session_id = login["id"].as_str().expect("Missing session ID");
+
+    let mut latencies = Vec::with_capacity(requests);
+    for _ in 0..requests {
+        let start = Instant::now();
+        send(&host, "GET", "/fish?limit=20", Some(session_id)).await;
+        latencies.push(start.elapsed());
+    }
+    latencies
+}
+
+/// Send a request on a new connection, and return the response body. Panic if
+/// the response isn't a 200
+async fn send(
+    host: &str,
+    method: &str,
+    path: &str,
+    session_id: Option<&str>,
+) -> String {
+    let mut stream = TcpStream::connect(host)
+        .await
+        .expect("Error connecting to server");
+    let authorization = session_id
+        .map(|id| format!("Authorization: Bearer {id}\r\n"))
+        .unwrap_or_default();
+    let request = format!(
+        "{method} {path} HTTP/1.1\r\nHost: {host}\r\n{authorization}\
+        Content-Length: 0\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    let (head, body) =
+        response.split_once("\r\n\r\n").expect("Malformed response");
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "{method} {path} failed: {head}"
+    );
+    body.to_owned()
+}
+
+fn env_number(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
@@ -0,0 +1,312 @@
+//! Credentials that identify a session. Every scheme carries the session ID,
+//! and they differ only in how it's encoded, so clients can test each one.
+//! Supported schemes, in order of precedence:
+//! - `Authorization: Bearer <session_id>`, or a signed session token (JWT)
+//! - `Authorization: Basic`, with the session ID as the username. The password
+//!   is ignored.
+//! - `Authorization: Digest` (RFC 7616), with the session ID as both the
+//!   username and password
+//! - `Shoal-Session-Id: <session_id>`
+//! - `X-API-Key: <session_id>`
+//! - `?api_key=<session_id>` query parameter
+//! - `shoal_session=<session_id>` cookie, which `POST /login` can set
+
+use crate::{
+    Error,
+    jwt::{Claims, Keys},
+    scope::Scope,
+};
+use axum::http::{HeaderValue, Method, Uri, header, request::Parts};
+use axum_extra::extract::CookieJar;
+use base64::{
+    Engine,
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+};
+use hmac::{Hmac, Mac};
+use jiff::Timestamp;
+use md5::Md5;
+use serde::Deserialize;
+use sha2::{Digest as _, Sha256};
+use std::{collections::HashMap, iter, sync::LazyLock};
+
+/// Protection space for all challenges
+const REALM: &str = "shoal";
+/// Header with the bare session ID
+const SESSION_ID_HEADER: &str = "Shoal-Session-Id";
+/// Header with the session ID as an API key
+const API_KEY_HEADER: &str = "X-API-Key";
+/// Cookie with the session ID
+pub const SESSION_COOKIE: &str = "shoal_session";
+/// How long a Digest nonce can be used before the client has to get a new
+/// one
+const NONCE_LIFETIME_SECONDS: i64 = 5 * 60;
+
+/// Key for signing Digest nonces. Nonces are stateless, so the server can
+/// verify that it issued a nonce without tracking them. A new key is
+/// generated on startup, so nonces from a previous run are rejected.
+static NONCE_KEY: LazyLock<[u8; 32]> = LazyLock::new(rand::random);
+
+/// Credentials from a request
+pub enum Credentials {
+    /// A session ID. The session still needs to be checked
+    SessionId(String),
+    /// A verified session token. It's trusted without checking the session
+    Token(Claims),
+}
+
+/// Get the session from the request's credentials. Return `None` if there
+/// are no credentials.
+pub fn credentials(
+    parts: &Parts,
+    keys: &Keys,
+) -> crate::Result<Option<Credentials>> {
+    if let Some(authorization) = parts.headers.get(header::AUTHORIZATION) {
+        let (scheme, credentials) = authorization
+            .to_str()
+            .ok() // Should be valid UTF-8
+            .and_then(|authorization| authorization.split_once(' '))
+            .ok_or(Error::InvalidAuthorization)?;
+        let credentials = credentials.trim();
+        let session_id = if scheme.eq_ignore_ascii_case("Bearer") {
+            // Session IDs are hex, so a dot means it's a JWT
+            if credentials.contains('.') {
+                return Ok(Some(Credentials::Token(keys.verify(credentials)?)));
+            }
+            credentials.to_owned()
+        } else if scheme.eq_ignore_ascii_case("Basic") {
+            basic(credentials)?
+        } else if scheme.eq_ignore_ascii_case("Digest") {
+            digest(&parts.method, &parts.uri, credentials)?
+        } else {
+            return Err(Error::InvalidAuthorization);
+        };
+        return Ok(Some(Credentials::SessionId(session_id)));
+    }
+
+    for name in [SESSION_ID_HEADER, API_KEY_HEADER] {
+        if let Some(value) = parts.headers.get(name) {
+            // Session IDs are ASCII, so a non-UTF-8 value won't match any
+            // session anyway
+            let value = String::from_utf8_lossy(value.as_bytes());
+            return Ok(Some(Credentials::SessionId(value.into_owned())));
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct ApiKeyQuery {
+        api_key: Option<String>,
+    }
+    // Other query params are the route's business, so ignore any errors here
+    let api_key = parts
+        .uri
+        .query()
+        .and_then(|query| serde_urlencoded::from_str(query).ok())
+        .and_then(|query: ApiKeyQuery| query.api_key);
+    if let Some(api_key) = api_key {
+        return Ok(Some(Credentials::SessionId(api_key)));
+    }
+
+    let cookie = CookieJar::from_headers(&parts.headers)
+        .get(SESSION_COOKIE)
+        .map(|cookie| cookie.value().to_owned());
+    Ok(cookie.map(Credentials::SessionId))
+}
+
+/// `WWW-Authenticate` challenges for a 401 response, one per scheme. If
+/// `stale` is set, the Digest challenges tell the client its nonce expired
+/// and it can retry with the new one without prompting the user.
+pub fn challenges(stale: bool) -> Vec<HeaderValue> {
+    let nonce = nonce(Timestamp::now().as_second());
+    let stale = if stale { ", stale=true" } else { "" };
+    let digest = |algorithm: Algorithm| {
+        format!(
+            "Digest realm=\"{REALM}\", qop=\"auth\", algorithm={}, \
+            nonce=\"{nonce}\"{stale}",
+            algorithm.name()
+        )
+    };
+    [
+        format!("Bearer realm=\"{REALM}\""),
+        format!("Basic realm=\"{REALM}\", charset=\"UTF-8\""),
+        // Preferred algorithm first
+        digest(Algorithm::Sha256),
+        digest(Algorithm::Md5),
+    ]
+    .into_iter()
+    .map(|challenge| {
+        HeaderValue::from_str(&challenge).expect("Challenge is ASCII")
+    })
+    .collect()
+}
+
+/// `WWW-Authenticate` challenge for a 403 response, telling the client which
+/// scope it needs
+pub fn insufficient_scope(required: Scope) -> HeaderValue {
+    HeaderValue::from_str(&format!(
+        "Bearer realm=\"{REALM}\", error=\"insufficient_scope\", \
+        scope=\"{required}\""
+    ))
+    .expect("Challenge is ASCII")
+}
+
+/// Decode Basic credentials (RFC 7617) and return the username
+fn basic(credentials: &str) -> crate::Result<String> {
+    let decoded = BASE64_STANDARD
+        .decode(credentials)
+        .ok()
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .ok_or(Error::InvalidAuthorization)?;
+    let (username, _) =
+        decoded.split_once(':').ok_or(Error::InvalidAuthorization)?;
+    if username.is_empty() {
+        return Err(Error::InvalidAuthorization);
+    }
+    Ok(username.to_owned())
+}
+
+/// Verify Digest credentials (RFC 7616) and return the username. Only
+/// `qop=auth` is supported.
+fn digest(
+    method: &Method,
+    uri: &Uri,
+    credentials: &str,
+) -> crate::Result<String> {
+    let params =
+        parse_params(credentials).ok_or(Error::InvalidAuthorization)?;
+    let param = |name: &str| {
+        params
+            .get(name)
+            .map(String::as_str)
+            .ok_or(Error::InvalidAuthorization)
+    };
+    let username = param("username")?;
+    let nonce = param("nonce")?;
+    let digest_uri = param("uri")?;
+    let nc = param("nc")?;
+    let cnonce = param("cnonce")?;
+    // MD5 is the default for compatibility with RFC 2617
+    let algorithm = match params.get("algorithm") {
+        None => Algorithm::Md5,
+        Some(algorithm) => Algorithm::from_name(algorithm)
+            .ok_or(Error::InvalidAuthorization)?,
+    };
+    if param("qop")? != "auth" {
+        return Err(Error::InvalidAuthorization);
+    }
+    // The URI is covered by the response hash, so it must be the one that
+    // was actually requested
+    let request_uri = uri.path_and_query().map_or("/", |path| path.as_str());
+    if digest_uri != request_uri {
+        return Err(Error::InvalidAuthorization);
+    }
+    if param("realm")? != REALM {
+        return Err(Error::InvalidCredentials);
+    }
+
+    let issued_at = verify_nonce(nonce).ok_or(Error::InvalidCredentials)?;
+    // The password is the session ID, same as the username
+    let ha1 = algorithm.hash(&format!("{username}:{REALM}:{username}"));
+    let ha2 = algorithm.hash(&format!("{method}:{digest_uri}"));
+    let expected =
+        algorithm.hash(&format!("{ha1}:{nonce}:{nc}:{cnonce}:auth:{ha2}"));
+    if !param("response")?.eq_ignore_ascii_case(&expected) {
+        return Err(Error::InvalidCredentials);
+    }
+    // Only report staleness for otherwise correct credentials, so the client
+    // knows it can retry without new credentials
+    if Timestamp::now().as_second() - issued_at > NONCE_LIFETIME_SECONDS {
+        return Err(Error::StaleNonce);
+    }
+    Ok(username.to_owned())
+}
+
+/// Generate a Digest nonce, which encodes the time it was issued and a
+/// signature
+fn nonce(issued_at: i64) -> String {
+    let signature = nonce_mac(issued_at).finalize().into_bytes();
+    format!("{issued_at}.{}", BASE64_URL_SAFE_NO_PAD.encode(signature))
+}
+
+/// Check that the server issued a nonce, and return the time it was issued
+fn verify_nonce(nonce: &str) -> Option<i64> {
+    let (issued_at, signature) = nonce.split_once('.')?;
+    let issued_at = issued_at.parse().ok()?;
+    let signature = BASE64_URL_SAFE_NO_PAD.decode(signature).ok()?;
+    nonce_mac(issued_at).verify_slice(&signature).ok()?;
+    Some(issued_at)
+}
+
+fn nonce_mac(issued_at: i64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(NONCE_KEY.as_slice())
+        .expect("HMAC accepts keys of any length");
+    mac.update(&issued_at.to_be_bytes());
+    mac
+}
+
+/// Parse the comma-separated `name=value` params of a Digest header. Values
+/// may be quoted strings. Names are lowercased. Return `None` if malformed.
+fn parse_params(input: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    let mut chars = input.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+        if chars.peek().is_none() {
+            return Some(params);
+        }
+
+        let name: String =
+            iter::from_fn(|| chars.next_if(|c| *c != '=')).collect();
+        chars.next()?; // =
+        let value = if chars.next_if_eq(&'"').is_some() {
+            let mut value = String::new();
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => value.push(chars.next()?),
+                    c => value.push(c),
+                }
+            }
+            value
+        } else {
+            let value: String =
+                iter::from_fn(|| chars.next_if(|c| *c != ',')).collect();
+            value.trim().to_owned()
+        };
+        params.insert(name.trim().to_ascii_lowercase(), value);
+    }
+}
+
+/// Hash algorithms for Digest auth
+#[derive(Copy, Clone, Debug)]
+enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    fn from_name(name: &str) -> Option<Self> {
+        if name.eq_ignore_ascii_case("MD5") {
+            Some(Self::Md5)
+        } else if name.eq_ignore_ascii_case("SHA-256") {
+            Some(Self::Sha256)
+        } else {
+            None
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Md5 => "MD5",
+            Self::Sha256 => "SHA-256",
+        }
+    }
+
+    /// Hash a value and return it as lowercase hex
+    fn hash(self, value: &str) -> String {
+        let bytes = match self {
+            Self::Md5 => Md5::digest(value).to_vec(),
+            Self::Sha256 => Sha256::digest(value).to_vec(),
+        };
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+}
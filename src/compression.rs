@@ -0,0 +1,202 @@
+//! Content codings (RFC 9110 section 8.4). Responses are compressed according
+//! to `Accept-Encoding`, and some routes accept compressed request bodies.
+
+use crate::Error;
+use axum::{
+    body::Body,
+    extract::{FromRequest, Request},
+    http::{Extensions, HeaderMap, HeaderValue, StatusCode, Version, header},
+    middleware::Next,
+    response::Response,
+};
+use bytes::Bytes;
+use flate2::{
+    Compression,
+    read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder},
+    write::{GzEncoder, ZlibEncoder},
+};
+use std::{
+    fmt::{self, Display},
+    io::{Read, Write},
+};
+use tower_http::compression::{CompressionLayer, DefaultPredicate, Predicate};
+
+/// Largest request body we'll decompress to. This matches axum's default
+/// limit for uncompressed bodies, and protects against compression bombs.
+const MAX_DECODED_SIZE: usize = 2 * 1024 * 1024;
+
+/// A supported content coding
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+    Brotli,
+    Zstd,
+}
+
+impl Encoding {
+    pub const ALL: &[Self] =
+        &[Self::Gzip, Self::Deflate, Self::Brotli, Self::Zstd];
+
+    /// Name of the coding in `Content-Encoding` and `Accept-Encoding`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// Names of every coding
+    pub fn names() -> Vec<&'static str> {
+        Self::ALL.iter().map(|encoding| encoding.name()).collect()
+    }
+
+    /// Get a coding by name. `x-gzip` is accepted as an alias, as required by
+    /// RFC 9110.
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|encoding| name.eq_ignore_ascii_case(encoding.name()))
+            .or_else(|| {
+                name.eq_ignore_ascii_case("x-gzip").then_some(Self::Gzip)
+            })
+    }
+
+    /// Compress data
+    pub fn encode(self, data: &[u8]) -> Vec<u8> {
+        let encoded = match self {
+            Self::Gzip => {
+                let mut encoder =
+                    GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data).and_then(|()| encoder.finish())
+            }
+            // HTTP's deflate is zlib-wrapped (RFC 9110 section 8.4.1.2)
+            Self::Deflate => {
+                let mut encoder =
+                    ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data).and_then(|()| encoder.finish())
+            }
+            Self::Brotli => {
+                let mut encoder =
+                    brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                encoder.write_all(data).map(|()| encoder.into_inner())
+            }
+            Self::Zstd => zstd::encode_all(data, 0),
+        };
+        encoded.expect("Compressing into a Vec is infallible")
+    }
+
+    /// Decompress data, up to [MAX_DECODED_SIZE]
+    fn decode(self, data: &[u8]) -> crate::Result<Vec<u8>> {
+        let reader: Box<dyn Read + '_> = match self {
+            Self::Gzip => Box::new(MultiGzDecoder::new(data)),
+            // Some clients send raw deflate data without the zlib wrapper, so
+            // accept that too. A zlib header is a checksummed pair of bytes
+            Self::Deflate
+                if data.len() >= 2
+                    && data[0] & 0x0f == 8
+                    && u16::from_be_bytes([data[0], data[1]]) % 31 == 0 =>
+            {
+                Box::new(ZlibDecoder::new(data))
+            }
+            Self::Deflate => Box::new(DeflateDecoder::new(data)),
+            Self::Brotli => Box::new(brotli::Decompressor::new(data, 4096)),
+            Self::Zstd => {
+                Box::new(zstd::Decoder::new(data).map_err(|error| {
+                    Error::InvalidEncoding {
+                        encoding: self,
+                        message: error.to_string(),
+                    }
+                })?)
+            }
+        };
+        let mut decoded = Vec::new();
+        reader
+            .take(MAX_DECODED_SIZE as u64 + 1)
+            .read_to_end(&mut decoded)
+            .map_err(|error| Error::InvalidEncoding {
+                encoding: self,
+                message: error.to_string(),
+            })?;
+        if decoded.len() > MAX_DECODED_SIZE {
+            return Err(Error::PayloadTooLarge {
+                limit: MAX_DECODED_SIZE,
+            });
+        }
+        Ok(decoded)
+    }
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The `Content-Encoding` of a request body before [decompress] decoded it
+#[derive(Clone, Debug)]
+pub struct ReceivedEncoding(pub String);
+
+/// Layer to compress responses in whichever coding the client prefers.
+/// Besides the default exclusions (tiny bodies, images, and event streams),
+/// responses that support byte ranges are left alone, because ranges refer to
+/// the encoded bytes. So are responses with an `ETag`, because a strong tag
+/// identifies the exact bytes, and compressing would give two different
+/// bodies the same tag.
+pub fn compression() -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new().compress_when(DefaultPredicate::new().and(
+        |_: StatusCode, _: Version, headers: &HeaderMap, _: &Extensions| {
+            !headers.contains_key(header::ACCEPT_RANGES)
+                && !headers.contains_key(header::ETAG)
+        },
+    ))
+}
+
+/// Middleware to decompress request bodies according to their
+/// `Content-Encoding`. Multiple codings are decoded in reverse order. The
+/// original header is moved to a [ReceivedEncoding] extension, so the route
+/// sees the body as if it had never been compressed.
+pub async fn decompress(
+    request: Request,
+    next: Next,
+) -> crate::Result<Response> {
+    let (mut parts, body) = request.into_parts();
+    let Some(content_encoding) = parts.headers.remove(header::CONTENT_ENCODING)
+    else {
+        return Ok(next.run(Request::from_parts(parts, body)).await);
+    };
+    let received = String::from_utf8_lossy(content_encoding.as_bytes());
+    let encodings = received
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.eq_ignore_ascii_case("identity"))
+        .map(|name| {
+            Encoding::from_name(name)
+                .ok_or_else(|| Error::UnsupportedEncoding(name.to_owned()))
+        })
+        .collect::<crate::Result<Vec<_>>>()?;
+
+    // A fresh request gets axum's default body limit
+    let data = Bytes::from_request(Request::new(body), &()).await?.to_vec();
+    // Decompressing is CPU-heavy, so keep it off the async runtime
+    let data = tokio::task::spawn_blocking(move || {
+        encodings
+            .into_iter()
+            .rev()
+            .try_fold(data, |data, encoding| encoding.decode(&data))
+    })
+    .await
+    .expect("Decompression panicked")?;
+
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(data.len()));
+    parts
+        .extensions
+        .insert(ReceivedEncoding(received.into_owned()));
+    let request = Request::from_parts(parts, Body::from(data));
+    Ok(next.run(request).await)
+}
@@ -0,0 +1,220 @@
+//! Conditional requests (RFC 9110 section 13), based on each fish's version
+//! and modification time
+
+use crate::{Error, data::Fish, format::Format};
+use axum::{
+    extract::FromRequestParts,
+    http::{
+        HeaderMap, HeaderName, HeaderValue, StatusCode, header, request::Parts,
+    },
+    response::{IntoResponse, Response},
+};
+use jiff::{Timestamp, fmt::rfc2822};
+use std::convert::Infallible;
+
+/// Get the strong entity tag for a fish in a format. This changes whenever
+/// the fish is modified, and is never repeated by a new fish that reuses the
+/// ID. Each format is a different representation with different bytes, so it
+/// gets its own tag.
+pub fn etag(fish: &Fish, format: Format) -> String {
+    let (_, subtype) = format
+        .media_type()
+        .split_once('/')
+        .expect("Media type has a subtype");
+    format!("\"{}-{}-{subtype}\"", fish.key, fish.version)
+}
+
+/// Headers that allow the client to make conditional requests for a fish
+pub fn validators(
+    fish: &Fish,
+    format: Format,
+) -> [(HeaderName, HeaderValue); 2] {
+    [
+        (
+            header::ETAG,
+            HeaderValue::from_str(&etag(fish, format)).expect("ETag is ASCII"),
+        ),
+        (
+            header::LAST_MODIFIED,
+            HeaderValue::from_str(&http_date(fish.updated_at))
+                .expect("HTTP date is ASCII"),
+        ),
+    ]
+}
+
+/// A `304 Not Modified` response for a fish
+pub fn not_modified(fish: &Fish, format: Format) -> Response {
+    (StatusCode::NOT_MODIFIED, validators(fish, format)).into_response()
+}
+
+/// Conditional request headers. Malformed headers are ignored, as recommended
+/// by the RFC.
+#[derive(Clone, Debug, Default)]
+pub struct Conditions {
+    /// Format negotiated from `Accept`. Entity tags are compared against the
+    /// tag of the fish in this format, the same one the response would have.
+    format: Format,
+    if_match: Option<EntityTags>,
+    if_none_match: Option<EntityTags>,
+    if_modified_since: Option<Timestamp>,
+    if_unmodified_since: Option<Timestamp>,
+}
+
+impl Conditions {
+    /// For a read request, does the client already have the current version
+    /// of the fish? If so, we should respond with `304 Not Modified`.
+    pub fn is_not_modified(&self, fish: &Fish) -> bool {
+        // If-Modified-Since is only checked if If-None-Match is absent
+        if let Some(if_none_match) = &self.if_none_match {
+            if_none_match.matches_weak(&etag(fish, self.format))
+        } else if let Some(since) = self.if_modified_since {
+            truncate(fish.updated_at) <= since
+        } else {
+            false
+        }
+    }
+
+    /// For a mutation, check that the fish is in the state the client expects.
+    /// Return `412 Precondition Failed` if not.
+    pub fn check_preconditions(&self, fish: &Fish) -> crate::Result<()> {
+        let etag = etag(fish, self.format);
+        // If-Unmodified-Since is only checked if If-Match is absent
+        let passed = if let Some(if_match) = &self.if_match {
+            if_match.matches_strong(&etag)
+        } else if let Some(since) = self.if_unmodified_since {
+            truncate(fish.updated_at) <= since
+        } else {
+            true
+        };
+        let passed = passed
+            && !self
+                .if_none_match
+                .as_ref()
+                .is_some_and(|if_none_match| if_none_match.matches_weak(&etag));
+        if passed {
+            Ok(())
+        } else {
+            Err(Error::PreconditionFailed)
+        }
+    }
+
+    /// For a request that would create a new fish, check that the client
+    /// wasn't expecting an existing one. `If-Match` can never match a fish
+    /// that doesn't exist.
+    pub fn check_create_preconditions(&self) -> crate::Result<()> {
+        if self.if_match.is_some() {
+            Err(Error::PreconditionFailed)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Conditions {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        // If no format is acceptable, the handler's own Format extractor
+        // rejects the request, so the fallback is never used
+        let format = Format::from_request_parts(parts, state)
+            .await
+            .unwrap_or_default();
+        let headers = &parts.headers;
+        Ok(Self {
+            format,
+            if_match: get_header(headers, header::IF_MATCH)
+                .map(EntityTags::parse),
+            if_none_match: get_header(headers, header::IF_NONE_MATCH)
+                .map(EntityTags::parse),
+            if_modified_since: get_header(headers, header::IF_MODIFIED_SINCE)
+                .and_then(parse_http_date),
+            if_unmodified_since: get_header(
+                headers,
+                header::IF_UNMODIFIED_SINCE,
+            )
+            .and_then(parse_http_date),
+        })
+    }
+}
+
+/// Value of an `If-Match` or `If-None-Match` header
+#[derive(Clone, Debug)]
+enum EntityTags {
+    /// `*`
+    Any,
+    /// A list of tags, with their quotes intact
+    List(Vec<EntityTag>),
+}
+
+impl EntityTags {
+    fn parse(value: &str) -> Self {
+        if value.trim() == "*" {
+            return Self::Any;
+        }
+        let tags = value
+            .split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| match tag.strip_prefix("W/") {
+                Some(tag) => EntityTag {
+                    weak: true,
+                    tag: tag.to_owned(),
+                },
+                None => EntityTag {
+                    weak: false,
+                    tag: tag.to_owned(),
+                },
+            })
+            .collect();
+        Self::List(tags)
+    }
+
+    /// Strong comparison: weak tags never match
+    fn matches_strong(&self, etag: &str) -> bool {
+        match self {
+            Self::Any => true,
+            Self::List(tags) => {
+                tags.iter().any(|tag| !tag.weak && tag.tag == etag)
+            }
+        }
+    }
+
+    /// Weak comparison: the weak flag is ignored
+    fn matches_weak(&self, etag: &str) -> bool {
+        match self {
+            Self::Any => true,
+            Self::List(tags) => tags.iter().any(|tag| tag.tag == etag),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct EntityTag {
+    weak: bool,
+    tag: String,
+}
+
+fn get_header(headers: &HeaderMap, name: HeaderName) -> Option<&str> {
+    headers.get(name)?.to_str().ok()
+}
+
+/// Format a timestamp as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+fn http_date(timestamp: Timestamp) -> String {
+    rfc2822::DateTimePrinter::new()
+        .timestamp_to_rfc9110_string(&timestamp)
+        .expect("Timestamp is in range for HTTP dates")
+}
+
+fn parse_http_date(value: &str) -> Option<Timestamp> {
+    rfc2822::DateTimeParser::new().parse_timestamp(value).ok()
+}
+
+/// HTTP dates have one-second resolution, so drop the sub-second component
+/// before comparing
+fn truncate(timestamp: Timestamp) -> Timestamp {
+    Timestamp::from_second(timestamp.as_second())
+        .expect("Truncated timestamp is in range")
+}
@@ -1,27 +1,43 @@
 use crate::{
     Error,
-    routes::{CreateFishRequest, LoginResponse, UpdateFishRequest},
+    auth::{self, Credentials},
+    conditional::Conditions,
+    events::{Event, EventHub, EventKind},
+    jwt::Keys,
+    pool::Pool,
+    routes::{
+        BulkOperation, CreateFishRequest, ListFishQuery, LoginResponse,
+        SessionSnapshot, SnapshotFish, UpdateFishRequest,
+    },
+    scope::{Scope, Scopes},
+    validate::Validator,
 };
 use axum::{
     Extension, RequestPartsExt,
     extract::FromRequestParts,
-    http::{header, request::Parts},
+    http::request::Parts,
     response::{IntoResponse, Response},
 };
+use base64::{
+    Engine,
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD as URL_SAFE_NO_PAD},
+};
+use futures::{Stream, TryStreamExt, stream};
+use image::{DynamicImage, ImageFormat};
 use jiff::Timestamp;
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::IndexedRandom};
 use rusqlite::{
-    Connection, Row, ToSql, named_params,
-    types::{FromSql, FromSqlResult, ToSqlOutput, ValueRef},
+    Connection, OptionalExtension, Row, ToSql, named_params,
+    types::{FromSql, FromSqlResult, ToSqlOutput, Type, Value, ValueRef},
 };
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
 use std::{
     fmt::{self, Display},
-    ops::Deref,
+    io,
     path::Path,
     sync::Arc,
     time::Duration,
 };
-use tokio::sync::Mutex;
 use tracing::info;
 
 /// Default fish defined for all users
@@ -52,183 +68,1266 @@ static FISHES: &[StaticFish] = &[
     },
 ];
 
-/// In-memory database for fish. This uses an Arc so it is safe and cheap to
-/// clone.
+/// Schema migrations, applied in order. The DB's `user_version` tracks how
+/// many have been applied. Never modify a migration once it's released; add a
+/// new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: Initial schema
+    "CREATE TABLE session (
+        id TEXT PRIMARY KEY,
+        expires_at TEXT
+    );
+    CREATE TABLE fish (
+        id INTEGER PRIMARY KEY,
+        session_id TEXT,
+        name TEXT NOT NULL,
+        species TEXT NOT NULL,
+        age INTEGER NOT NULL,
+        weight_kg REAL NOT NULL,
+        version INTEGER NOT NULL DEFAULT 1,
+        updated_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ')),
+        FOREIGN KEY(session_id) REFERENCES session(id) ON DELETE CASCADE
+    );
+    CREATE TABLE image (
+        fish_id INTEGER PRIMARY KEY,
+        content_type TEXT NOT NULL,
+        data BLOB NOT NULL,
+        FOREIGN KEY(fish_id) REFERENCES fish(id) ON DELETE CASCADE
+    );",
+    // 2: Every fish query is filtered by session
+    "CREATE INDEX fish_session_id ON fish(session_id);",
+    // 3: Sessions have their own lifetime, used when refreshing them
+    "ALTER TABLE session ADD COLUMN ttl_seconds INTEGER NOT NULL DEFAULT 3600;",
+    // 4: OAuth grants
+    "CREATE TABLE oauth_code (
+        code TEXT PRIMARY KEY,
+        client_id TEXT NOT NULL,
+        redirect_uri TEXT NOT NULL,
+        code_challenge TEXT NOT NULL,
+        code_challenge_method TEXT NOT NULL,
+        scope TEXT,
+        expires_at TEXT NOT NULL
+    );
+    CREATE TABLE oauth_refresh_token (
+        token TEXT PRIMARY KEY,
+        session_id TEXT NOT NULL,
+        client_id TEXT NOT NULL,
+        scope TEXT,
+        FOREIGN KEY(session_id) REFERENCES session(id) ON DELETE CASCADE
+    );",
+    // 5: Sessions are granted scopes. Existing sessions keep full access
+    "ALTER TABLE session ADD COLUMN scope TEXT NOT NULL
+        DEFAULT 'fish:read fish:write fish:delete';",
+    // 6: Fish IDs are unique per session rather than globally, so clients can
+    // pick IDs without colliding with other sessions. The global key is only
+    // used internally, e.g. to attach images
+    "CREATE TABLE fish_new (
+        key INTEGER PRIMARY KEY,
+        id INTEGER NOT NULL,
+        session_id TEXT,
+        name TEXT NOT NULL,
+        species TEXT NOT NULL,
+        age INTEGER NOT NULL,
+        weight_kg REAL NOT NULL,
+        version INTEGER NOT NULL DEFAULT 1,
+        updated_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ')),
+        FOREIGN KEY(session_id) REFERENCES session(id) ON DELETE CASCADE
+    );
+    INSERT INTO fish_new (key, id, session_id, name, species, age, weight_kg,
+        version, updated_at)
+    SELECT id, id, session_id, name, species, age, weight_kg, version,
+        updated_at
+    FROM fish;
+    CREATE TABLE image_new (
+        fish_key INTEGER PRIMARY KEY,
+        content_type TEXT NOT NULL,
+        data BLOB NOT NULL,
+        FOREIGN KEY(fish_key) REFERENCES fish_new(key) ON DELETE CASCADE
+    );
+    INSERT INTO image_new (fish_key, content_type, data)
+    SELECT fish_id, content_type, data FROM image;
+    DROP TABLE image;
+    DROP TABLE fish;
+    ALTER TABLE fish_new RENAME TO fish;
+    ALTER TABLE image_new RENAME TO image;
+    CREATE UNIQUE INDEX fish_session_id ON fish(session_id, id);",
+    // 7: Keys are never reused, so they can identify a fish in its ETag even
+    // after its ID is taken by another fish
+    "CREATE TABLE fish_new (
+        key INTEGER PRIMARY KEY AUTOINCREMENT,
+        id INTEGER NOT NULL,
+        session_id TEXT,
+        name TEXT NOT NULL,
+        species TEXT NOT NULL,
+        age INTEGER NOT NULL,
+        weight_kg REAL NOT NULL,
+        version INTEGER NOT NULL DEFAULT 1,
+        updated_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ')),
+        FOREIGN KEY(session_id) REFERENCES session(id) ON DELETE CASCADE
+    );
+    INSERT INTO fish_new SELECT * FROM fish;
+    CREATE TABLE image_new (
+        fish_key INTEGER PRIMARY KEY,
+        content_type TEXT NOT NULL,
+        data BLOB NOT NULL,
+        FOREIGN KEY(fish_key) REFERENCES fish_new(key) ON DELETE CASCADE
+    );
+    INSERT INTO image_new SELECT * FROM image;
+    DROP TABLE image;
+    DROP INDEX fish_session_id;
+    DROP TABLE fish;
+    ALTER TABLE fish_new RENAME TO fish;
+    ALTER TABLE image_new RENAME TO image;
+    CREATE UNIQUE INDEX fish_session_id ON fish(session_id, id);",
+];
+
+/// Highest ID a client can give a fish it creates with `PUT`. New fish are
+/// numbered after the highest ID in their session, so this leaves room for
+/// billions more before the IDs would overflow.
+const MAX_CHOSEN_ID: u32 = i32::MAX as u32;
+
+/// Most images a session can have. Snapshots include every image, so this
+/// bounds their size.
+pub const MAX_SESSION_IMAGES: usize = 20;
+
+/// How long an OAuth authorization code can be exchanged for tokens
+const AUTHORIZATION_CODE_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Number of read-only connections. Reads in different requests only wait on
+/// each other once they're all in use.
+const READER_COUNT: usize = 8;
+
+/// Number of fish to read at once for an export. Each page is read with its
+/// own query, so this is also how much is buffered ahead of the client.
+const EXPORT_PAGE_SIZE: usize = 500;
+
+/// How long a connection waits for another to release its lock on the DB
+/// before giving up
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Database for fish. This uses an Arc so it is safe and cheap to clone.
 #[derive(Clone, Debug)]
 pub struct Store {
-    /// SQLite DB. Mutex needed to allow multiple connections to access the DB
-    /// at once. Hopefully load is low enough that this isn't an issue
-    connection: Arc<Mutex<Connection>>,
+    /// Connection for all mutations. SQLite only allows one writer at a time
+    /// anyway, and funneling writes through a single connection makes each
+    /// read-check-write operation atomic.
+    writer: Arc<Pool>,
+    /// Read-only connections, so reads don't queue behind each other. With a
+    /// database file, WAL mode lets reads proceed during writes too. The
+    /// in-memory DB can't use WAL, so there a write waits for reads in
+    /// progress and blocks new ones until it commits. Nothing should hold a
+    /// read open for long.
+    readers: Arc<Pool>,
+    /// Notifications of changes to fish, per session
+    events: EventHub,
+    /// Lifetimes for new and refreshed sessions
+    session_ttl: SessionTtl,
 }
 
 impl Store {
-    pub fn new() -> crate::Result<Self> {
-        info!("Opening database");
-        let connection = Connection::open_in_memory()?;
-        connection.pragma_update(None, "foreign_keys", "ON")?;
-
-        // Initialize the DB
-        connection.execute(
-            "CREATE TABLE session (
-                id TEXT PRIMARY KEY,
-                expires_at TEXT
-            )",
-            (),
-        )?;
-        connection.execute(
-            "CREATE TABLE fish (
-                id INTEGER PRIMARY KEY,
-                session_id TEXT,
-                name TEXT NOT NULL,
-                species TEXT NOT NULL,
-                age INTEGER NOT NULL,
-                weight_kg REAL NOT NULL,
-                FOREIGN KEY(session_id) REFERENCES session(id) ON DELETE CASCADE
-            )",
+    /// Initialize the database. If `path` is given, the DB is stored in that
+    /// file, and sessions persist across restarts. Otherwise it's in memory.
+    /// `generated_fish` is the number of randomly generated fish to add to the
+    /// default set, on top of the static ones. Default fish are only added
+    /// when the DB is first created.
+    pub fn new(
+        path: Option<&Path>,
+        generated_fish: usize,
+        session_ttl: SessionTtl,
+    ) -> crate::Result<Self> {
+        let target = if let Some(path) = path {
+            info!(?path, "Opening database");
+            path.to_owned()
+        } else {
+            info!("Opening in-memory database");
+            // A plain :memory: DB is private to its connection. The memdb VFS
+            // lets all connections in the process share a DB by name.
+            format!("file:/shoal-{:016x}?vfs=memdb", rand::random::<u64>())
+                .into()
+        };
+
+        let mut writer = open_connection(&target)?;
+        if path.is_some() {
+            // WAL allows reads to proceed during a write, and is much less
+            // likely to lose data on a crash
+            writer.pragma_update(None, "journal_mode", "WAL")?;
+        }
+        migrate(&mut writer)?;
+
+        // Add default fish, unless they were added on a previous run
+        let has_default_fish: bool = writer.query_one(
+            "SELECT EXISTS (SELECT id FROM fish WHERE session_id IS NULL)",
             (),
+            |row| row.get(0),
         )?;
-        // Add default fish
-        for fish in FISHES {
-            connection.execute(
-                "INSERT INTO fish (name, species, age, weight_kg)
-                VALUES (:name, :species, :age, :weight_kg)",
-                named_params! {
-                    ":name": fish.name,
-                    ":species": fish.species,
-                    ":age": fish.age,
-                    ":weight_kg": fish.weight_kg,
-                },
-            )?;
+        if !has_default_fish {
+            let transaction = writer.transaction()?;
+            let generated = generate_fish(generated_fish);
+            for (id, fish) in (1..).zip(FISHES.iter().chain(&generated)) {
+                transaction.execute(
+                    "INSERT INTO fish (id, name, species, age, weight_kg)
+                    VALUES (:id, :name, :species, :age, :weight_kg)",
+                    named_params! {
+                        ":id": FishId(id),
+                        ":name": fish.name,
+                        ":species": fish.species,
+                        ":age": fish.age,
+                        ":weight_kg": fish.weight_kg,
+                    },
+                )?;
+            }
+            transaction.commit()?;
         }
 
+        resume_sessions(&writer)?;
+
+        let readers = (0..READER_COUNT)
+            .map(|_| {
+                let reader = open_connection(&target)?;
+                reader.pragma_update(None, "query_only", "ON")?;
+                Ok(reader)
+            })
+            .collect::<crate::Result<_>>()?;
         Ok(Self {
-            connection: Arc::new(Mutex::new(connection)),
+            writer: Pool::new(vec![writer]),
+            readers: Pool::new(readers),
+            events: EventHub::default(),
+            session_ttl,
         })
     }
 
-    /// Create a new session with a unique ID
-    pub async fn create_session(&self) -> crate::Result<LoginResponse> {
-        // Sessions last 1 hour
-        let expires_at =
-            (Timestamp::now() + Duration::from_secs(60 * 60)).to_string();
-        let conn = self.connection.lock().await;
-        let id = conn
-            // Generate an ID in the DB and return it
-            .prepare(
-                "INSERT INTO session (id, expires_at)
-                VALUES (lower(hex(randomblob(16))), :expires_at) RETURNING id",
-            )?
-            .query_one(named_params! { ":expires_at": &expires_at }, |row| {
-                row.get("id")
-            })?;
+    /// Create a new session with a unique ID, granted the given scopes. If
+    /// `ttl_seconds` isn't given, the session gets the default lifetime.
+    pub async fn create_session(
+        &self,
+        ttl_seconds: Option<u64>,
+        scope: Scopes,
+    ) -> crate::Result<LoginResponse> {
+        let ttl = self.session_ttl.resolve(ttl_seconds)?;
+        self.writer
+            .run(move |conn| {
+                let session = insert_session(conn, ttl, scope)?;
 
-        // Copy all default fish to the new session so they can be modified
-        conn.execute(
-            "INSERT INTO fish (session_id, name, species, age, weight_kg)
-            SELECT :session_id, name, species, age, weight_kg FROM fish
-                WHERE session_id is NULL",
-            named_params! { ":session_id": &id },
-        )?;
+                // Copy all default fish to the new session so they can be
+                // modified. They keep their IDs, which are per session
+                conn.execute(
+                    "INSERT INTO fish
+                        (id, session_id, name, species, age, weight_kg)
+                    SELECT id, :session_id, name, species, age, weight_kg
+                        FROM fish WHERE session_id is NULL",
+                    named_params! { ":session_id": &session.id },
+                )?;
+
+                Ok(session)
+            })
+            .await
+    }
 
-        Ok(LoginResponse { id, expires_at })
+    /// Get a view of the store for a single session, with all of its scopes.
+    /// The session isn't checked, so this should only be used with a known
+    /// session ID.
+    pub fn session(&self, session_id: SessionId) -> SessionStore {
+        SessionStore {
+            store: self.clone(),
+            session_id: Some(session_id),
+            scope: Scopes::all(),
+        }
     }
 
     /// Delete all expired sessions, returning their IDs
     pub async fn reap_sessions(&self) -> crate::Result<Vec<SessionId>> {
-        let conn = self.connection.lock().await;
-        let deleted: Vec<SessionId> = conn
-            .prepare(
-                "DELETE FROM session WHERE expires_at < :now RETURNING id",
-            )?
-            .query_map(
-                named_params! { ":now": Timestamp::now().to_string() },
-                |row| row.get::<_, SessionId>("id"),
-            )?
-            .collect::<Result<_, _>>()?;
-        Ok(deleted)
+        let events = self.events.clone();
+        self.writer
+            .run(move |conn| {
+                let deleted: Vec<SessionId> = conn
+                    .prepare(
+                        "DELETE FROM session WHERE expires_at < :now
+                        RETURNING id",
+                    )?
+                    .query_map(
+                        named_params! { ":now": Timestamp::now().to_string() },
+                        |row| row.get::<_, SessionId>("id"),
+                    )?
+                    .collect::<Result<_, _>>()?;
+                conn.execute(
+                    "DELETE FROM oauth_code WHERE expires_at < :now",
+                    named_params! { ":now": Timestamp::now().to_string() },
+                )?;
+                // Notify any listeners that the session is gone
+                for session_id in &deleted {
+                    events.close(session_id);
+                }
+                Ok(deleted)
+            })
+            .await
     }
 
     /// Dump the database to a file
     pub async fn dump(&self, path: &Path) -> crate::Result<()> {
-        let conn = self.connection.lock().await;
-        conn.backup("main", path, None)?;
-        Ok(())
+        let path = path.to_owned();
+        self.readers
+            .run(move |conn| {
+                conn.backup("main", path, None)?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Store an OAuth authorization code for a pending grant, and return the
+    /// code. The code expires shortly after.
+    pub async fn create_authorization_code(
+        &self,
+        grant: AuthorizationGrant,
+    ) -> crate::Result<String> {
+        let code = generate_token();
+        let expires_at =
+            (Timestamp::now() + AUTHORIZATION_CODE_LIFETIME).to_string();
+        self.writer
+            .run(move |conn| {
+                conn.execute(
+                    "INSERT INTO oauth_code (code, client_id, redirect_uri,
+                        code_challenge, code_challenge_method, scope,
+                        expires_at)
+                    VALUES (:code, :client_id, :redirect_uri, :code_challenge,
+                        :code_challenge_method, :scope, :expires_at)",
+                    named_params! {
+                        ":code": &code,
+                        ":client_id": grant.client_id,
+                        ":redirect_uri": grant.redirect_uri,
+                        ":code_challenge": grant.code_challenge,
+                        ":code_challenge_method": grant.code_challenge_method,
+                        ":scope": grant.scope,
+                        ":expires_at": expires_at,
+                    },
+                )?;
+                Ok(code)
+            })
+            .await
     }
 
-    /// Is the session in the store and unexpired?
-    async fn contains_session(
+    /// Consume an OAuth authorization code. Each code can only be used once.
+    /// Return `None` if the code doesn't exist or has expired.
+    pub async fn redeem_authorization_code(
+        &self,
+        code: String,
+    ) -> crate::Result<Option<AuthorizationGrant>> {
+        self.writer
+            .run(move |conn| {
+                let grant = conn
+                    .query_one(
+                        "DELETE FROM oauth_code
+                        WHERE code = :code AND expires_at > :now
+                        RETURNING *",
+                        named_params! {
+                            ":code": code,
+                            ":now": Timestamp::now().to_string(),
+                        },
+                        |row| row.try_into(),
+                    )
+                    .optional()?;
+                Ok(grant)
+            })
+            .await
+    }
+
+    /// Issue an OAuth refresh token for a session. The token is deleted
+    /// along with the session.
+    pub async fn create_refresh_token(
+        &self,
+        session_id: SessionId,
+        client_id: String,
+        scope: Scopes,
+    ) -> crate::Result<String> {
+        let token = generate_token();
+        self.writer
+            .run(move |conn| {
+                conn.execute(
+                    "INSERT INTO oauth_refresh_token
+                        (token, session_id, client_id, scope)
+                    VALUES (:token, :session_id, :client_id, :scope)",
+                    named_params! {
+                        ":token": &token,
+                        ":session_id": session_id,
+                        ":client_id": client_id,
+                        ":scope": scope,
+                    },
+                )?;
+                Ok(token)
+            })
+            .await
+    }
+
+    /// Consume an OAuth refresh token. Each token can only be used once;
+    /// a new one is issued with every refresh. Return `None` if the token
+    /// doesn't exist, or its session has expired.
+    pub async fn redeem_refresh_token(
+        &self,
+        token: String,
+    ) -> crate::Result<Option<RefreshGrant>> {
+        self.writer
+            .run(move |conn| {
+                let grant = conn
+                    .query_one(
+                        "DELETE FROM oauth_refresh_token
+                        WHERE token = :token AND session_id IN
+                            (SELECT id FROM session WHERE expires_at > :now)
+                        RETURNING *",
+                        named_params! {
+                            ":token": token,
+                            ":now": Timestamp::now().to_string(),
+                        },
+                        |row| {
+                            Ok(RefreshGrant {
+                                session_id: row.get("session_id")?,
+                                client_id: row.get("client_id")?,
+                            })
+                        },
+                    )
+                    .optional()?;
+                Ok(grant)
+            })
+            .await
+    }
+
+    /// Check if a session is in the store and hasn't expired
+    pub async fn has_session(&self, session_id: &str) -> crate::Result<bool> {
+        let scope = self
+            .session_scope(&SessionId(session_id.to_owned()))
+            .await?;
+        Ok(scope.is_some())
+    }
+
+    /// Get the scopes granted to a session. Return `None` if the session
+    /// isn't in the store or has expired.
+    async fn session_scope(
         &self,
         session_id: &SessionId,
-    ) -> crate::Result<bool> {
-        let conn = self.connection.lock().await;
-        let contains = conn
-            .prepare(
-                "SELECT EXISTS (SELECT id FROM session WHERE id = :id
-                AND expires_at > :now)",
-            )?
-            .query_one(
-                named_params! {
-                    ":id": session_id,
-                    ":now": Timestamp::now().to_string(),
-                },
-                |row| row.get::<_, bool>(0),
-            )?;
-        Ok(contains)
+    ) -> crate::Result<Option<Scopes>> {
+        let session_id = session_id.clone();
+        self.readers
+            .run(move |conn| {
+                let scope = conn
+                    .prepare(
+                        "SELECT scope FROM session WHERE id = :id
+                        AND expires_at > :now",
+                    )?
+                    .query_one(
+                        named_params! {
+                            ":id": session_id,
+                            ":now": Timestamp::now().to_string(),
+                        },
+                        |row| row.get("scope"),
+                    )
+                    .optional()?;
+                Ok(scope)
+            })
+            .await
+    }
+}
+
+/// Insert a new session with a unique ID, and no fish
+fn insert_session(
+    conn: &Connection,
+    ttl: Duration,
+    scope: Scopes,
+) -> crate::Result<LoginResponse> {
+    let expires_at = Timestamp::now() + ttl;
+    let id = conn
+        // Generate an ID in the DB and return it
+        .prepare(
+            "INSERT INTO session (id, expires_at, ttl_seconds, scope)
+            VALUES (lower(hex(randomblob(16))), :expires_at, :ttl_seconds,
+                :scope)
+            RETURNING id",
+        )?
+        .query_one(
+            named_params! {
+                ":expires_at": expires_at.to_string(),
+                ":ttl_seconds": ttl.as_secs(),
+                ":scope": scope,
+            },
+            |row| row.get("id"),
+        )?;
+    Ok(LoginResponse {
+        id,
+        expires_at,
+        scope,
+        token: None,
+    })
+}
+
+/// Generate a random opaque token, for OAuth codes and refresh tokens
+fn generate_token() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Make sure scopes requested for a session don't exceed the ones it was
+/// granted, so a session can't escalate its own permissions
+fn check_subset(scope: Scopes, granted: Scopes) -> crate::Result<()> {
+    match scope.excess(granted) {
+        Some(required) => Err(Error::Forbidden { required }),
+        None => Ok(()),
     }
 }
 
+/// Open a connection to the DB with the settings that every connection needs
+fn open_connection(target: &Path) -> crate::Result<Connection> {
+    let connection = Connection::open(target)?;
+    connection.busy_timeout(BUSY_TIMEOUT)?;
+    connection.pragma_update(None, "foreign_keys", "ON")?;
+    Ok(connection)
+}
+
+/// Apply any migrations that haven't been applied to the DB yet. Each
+/// migration runs in its own transaction along with the version bump, so a
+/// failure can't leave the schema half-migrated.
+fn migrate(connection: &mut Connection) -> crate::Result<()> {
+    let version: usize =
+        connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(Error::UnknownSchemaVersion {
+            version,
+            supported: MIGRATIONS.len(),
+        });
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let version = i + 1;
+        info!(version, "Migrating database");
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", version)?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+/// Drop sessions that expired while the server was down, and log the ones
+/// that are still active
+fn resume_sessions(connection: &Connection) -> crate::Result<()> {
+    let expired = connection.execute(
+        "DELETE FROM session WHERE expires_at < :now",
+        named_params! { ":now": Timestamp::now().to_string() },
+    )?;
+    let resumed: usize =
+        connection
+            .query_one("SELECT COUNT(*) FROM session", (), |row| row.get(0))?;
+    if expired > 0 || resumed > 0 {
+        info!(expired, resumed, "Loaded sessions from database");
+    }
+    Ok(())
+}
+
 /// A [Store] filtered to a single session's fish. This can be automatically
 /// extracted from a request by pulling the session ID from the
 /// `Shoal-Session-ID` header.
 ///
 /// A session is an isolated view of the database. Each user's session is unique
 /// and will not affect other sessions.
+#[derive(Clone)]
 pub struct SessionStore {
     store: Store,
     /// Session to show/modify fish for. If `None`, use the default fish and
     /// mutations will not be allowed
     session_id: Option<SessionId>,
+    /// Scopes granted to the request. This is the session's scopes, or the
+    /// token's if authenticated with a session token
+    scope: Scopes,
 }
 
 impl SessionStore {
-    /// List all fish for this session
-    pub async fn list(&self) -> crate::Result<Vec<Fish>> {
-        let conn = self.connection().await;
-        let fishes = conn
-            .prepare(
-                // NULL = NULL doesn't work so we need a special clause
-                "SELECT * FROM fish WHERE
-                session_id IS NULL AND :session_id IS NULL
-                OR session_id = :session_id",
-            )?
-            .query_map::<Fish, _, _>(
-                named_params! { ":session_id": self.session_id },
+    /// Get metadata for this session
+    pub async fn info(&self) -> crate::Result<SessionInfo> {
+        self.read(|this, conn| {
+            let session_id = this.session_id()?;
+            conn.query_one(
+                "SELECT * FROM session WHERE id = :id",
+                named_params! { ":id": session_id },
                 |row| row.try_into(),
-            )?
-            .collect::<std::result::Result<_, _>>()?;
-        Ok(fishes)
+            )
+            .optional()?
+            .ok_or_else(|| session_id.not_found())
+        })
+        .await
+    }
+
+    /// Check that the request was granted a scope. Without a session, this
+    /// is an authentication error rather than a permission error, because
+    /// logging in would grant it.
+    pub fn authorize(&self, scope: Scope) -> crate::Result<()> {
+        if self.scope.contains(scope) {
+            Ok(())
+        } else if self.session_id.is_none() {
+            Err(Error::Unauthenticated)
+        } else {
+            Err(Error::Forbidden { required: scope })
+        }
+    }
+
+    /// Extend this session's expiration. The new expiration is calculated
+    /// from now, using the given lifetime or the session's existing one. A
+    /// given lifetime replaces the session's lifetime for future refreshes.
+    /// Given scopes replace the session's scopes, but can only narrow them.
+    pub async fn refresh(
+        &self,
+        ttl_seconds: Option<u64>,
+        scope: Option<Scopes>,
+    ) -> crate::Result<SessionInfo> {
+        let ttl = ttl_seconds
+            .map(|seconds| self.store.session_ttl.resolve(Some(seconds)))
+            .transpose()?;
+        self.write(move |this, conn| {
+            let (current_ttl, granted) = this.grant(conn)?;
+            let ttl = ttl.unwrap_or(current_ttl);
+            if let Some(scope) = scope {
+                check_subset(scope, granted)?;
+            }
+            let info = conn.query_one(
+                "UPDATE session SET
+                    expires_at = :expires_at,
+                    ttl_seconds = :ttl_seconds,
+                    scope = coalesce(:scope, scope)
+                WHERE id = :id RETURNING *",
+                named_params! {
+                    ":id": this.session_id()?,
+                    ":expires_at": (Timestamp::now() + ttl).to_string(),
+                    ":ttl_seconds": ttl.as_secs(),
+                    ":scope": scope,
+                },
+                |row| row.try_into(),
+            )?;
+            Ok(info)
+        })
+        .await
+    }
+
+    /// Delete this session and all its fish. Subscribers to the session's
+    /// events are notified that it has expired.
+    pub async fn logout(&self) -> crate::Result<()> {
+        self.write(|this, conn| {
+            let session_id = this.session_id()?;
+            conn.execute(
+                "DELETE FROM session WHERE id = :id",
+                named_params! { ":id": session_id },
+            )?;
+            this.store.events.close(session_id);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Copy this session, including all its fish and their images, into a
+    /// new session. The copy gets its own expiration, using the given
+    /// lifetime or this session's lifetime. It gets the given scopes, which
+    /// can't exceed this session's, or the same scopes as this session. Fish
+    /// keep their IDs in the copy.
+    pub async fn fork(
+        &self,
+        ttl_seconds: Option<u64>,
+        scope: Option<Scopes>,
+    ) -> crate::Result<LoginResponse> {
+        let ttl = ttl_seconds
+            .map(|seconds| self.store.session_ttl.resolve(Some(seconds)))
+            .transpose()?;
+        self.write(move |this, conn| {
+            let source = this.session_id()?;
+            let (current_ttl, granted) = this.grant(conn)?;
+            let ttl = ttl.unwrap_or(current_ttl);
+            let scope = match scope {
+                Some(scope) => {
+                    check_subset(scope, granted)?;
+                    scope
+                }
+                None => granted,
+            };
+
+            let transaction = conn.unchecked_transaction()?;
+            let session = insert_session(&transaction, ttl, scope)?;
+            let params = named_params! {
+                ":source": source,
+                ":session_id": &session.id,
+            };
+            transaction.execute(
+                "INSERT INTO fish (id, session_id, name, species, age,
+                    weight_kg, version, updated_at)
+                SELECT id, :session_id, name, species, age, weight_kg,
+                    version, updated_at
+                FROM fish WHERE session_id = :source",
+                params,
+            )?;
+            // Match each image to its fish's copy by ID
+            transaction.execute(
+                "INSERT INTO image (fish_key, content_type, data)
+                SELECT copy.key, image.content_type, image.data FROM image
+                JOIN fish ON image.fish_key = fish.key
+                JOIN fish copy ON copy.session_id = :session_id
+                    AND copy.id = fish.id
+                WHERE fish.session_id = :source",
+                params,
+            )?;
+            transaction.commit()?;
+            Ok(session)
+        })
+        .await
+    }
+
+    /// Export all fish in this session, including their images
+    pub async fn snapshot(&self) -> crate::Result<SessionSnapshot> {
+        self.read(|this, conn| {
+            let fish = conn
+                .prepare(
+                    "SELECT fish.*, image.content_type, image.data FROM fish
+                    LEFT JOIN image ON image.fish_key = fish.key
+                    WHERE fish.session_id = :session_id
+                    ORDER BY fish.id",
+                )?
+                .query_map(
+                    named_params! { ":session_id": this.session_id()? },
+                    |row| {
+                        let image = row
+                            .get::<_, Option<String>>("content_type")?
+                            .map(|_| Image::try_from(row))
+                            .transpose()?;
+                        Ok(SnapshotFish {
+                            fish: CreateFishRequest {
+                                name: row.get("name")?,
+                                species: row.get("species")?,
+                                age: row.get("age")?,
+                                weight_kg: row.get("weight_kg")?,
+                            },
+                            image,
+                        })
+                    },
+                )?
+                .collect::<Result<_, _>>()?;
+            Ok(SessionSnapshot { fish })
+        })
+        .await
+    }
+
+    /// Replace all fish in this session with the contents of a snapshot. The
+    /// restored fish get new IDs. Return the restored fish.
+    pub async fn restore(
+        &self,
+        snapshot: SessionSnapshot,
+    ) -> crate::Result<Vec<Fish>> {
+        self.write(move |this, conn| {
+            let session_id = this.session_id()?;
+            let transaction = conn.unchecked_transaction()?;
+            let deleted = transaction
+                .prepare(
+                    "DELETE FROM fish WHERE session_id = :session_id
+                    RETURNING *",
+                )?
+                .query_map(
+                    named_params! { ":session_id": session_id },
+                    |row| Fish::try_from(row),
+                )?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let mut restored = Vec::with_capacity(snapshot.fish.len());
+            for SnapshotFish { fish: body, image } in snapshot.fish {
+                let fish = this.insert(&transaction, body)?;
+                if let Some(image) = image {
+                    this.attach_image(&transaction, fish.id, image)?;
+                }
+                restored.push(fish);
+            }
+            transaction.commit()?;
+
+            // Only notify once the changes are committed
+            for fish in deleted {
+                this.publish(EventKind::Deleted(fish))?;
+            }
+            for fish in &restored {
+                this.publish(EventKind::Created(fish.clone()))?;
+            }
+            Ok(restored)
+        })
+        .await
+    }
+
+    /// List fish for this session, with filtering, sorting, and pagination
+    pub async fn list(&self, query: &ListFishQuery) -> crate::Result<FishPage> {
+        let sort = query.sort.with_tiebreaker();
+
+        // Build the WHERE clause dynamically based on which filters are given
+        let mut conditions = vec![
+            // NULL = NULL doesn't work so we need a special clause
+            "(session_id IS NULL AND :session_id IS NULL
+                OR session_id = :session_id)"
+                .to_owned(),
+        ];
+        let mut params: Vec<(String, Value)> = vec![(
+            ":session_id".into(),
+            self.session_id.as_ref().map(|id| id.0.clone()).into(),
+        )];
+        let mut filter = |condition: &str, name: &str, value: Value| {
+            conditions.push(condition.to_owned());
+            params.push((name.to_owned(), value));
+        };
+        if let Some(species) = &query.species {
+            filter(
+                "species = :species COLLATE NOCASE",
+                ":species",
+                species.clone().into(),
+            );
+        }
+        if let Some(name) = &query.name_contains {
+            filter(
+                "instr(lower(name), lower(:name_contains)) > 0",
+                ":name_contains",
+                name.clone().into(),
+            );
+        }
+        if let Some(age) = query.min_age {
+            filter("age >= :min_age", ":min_age", age.into());
+        }
+        if let Some(age) = query.max_age {
+            filter("age <= :max_age", ":max_age", age.into());
+        }
+        if let Some(weight) = query.min_weight_kg {
+            filter(
+                "weight_kg >= :min_weight_kg",
+                ":min_weight_kg",
+                weight.into(),
+            );
+        }
+        if let Some(weight) = query.max_weight_kg {
+            filter(
+                "weight_kg <= :max_weight_kg",
+                ":max_weight_kg",
+                weight.into(),
+            );
+        }
+
+        let query = query.clone();
+        self.read(move |_, conn| {
+            // Count before applying the cursor, so it's the same for every page
+            let total = conn.query_one(
+                &format!(
+                    "SELECT COUNT(*) FROM fish WHERE {}",
+                    conditions.join(" AND ")
+                ),
+                named_params_ref(&params).as_slice(),
+                |row| row.get(0),
+            )?;
+
+            if let Some(cursor) = &query.cursor {
+                let (condition, cursor_params) = cursor.condition(&sort)?;
+                conditions.push(condition);
+                params.extend(cursor_params);
+            }
+
+            // Fetch an extra row so we know if there's another page
+            let limit = query.limit();
+            params.push((
+                ":limit".into(),
+                limit.map_or(-1, |limit| i64::from(limit) + 1).into(),
+            ));
+            params.push((":offset".into(), query.offset.unwrap_or(0).into()));
+            let mut items = conn
+                .prepare(&format!(
+                    "SELECT * FROM fish WHERE {} ORDER BY {}
+                    LIMIT :limit OFFSET :offset",
+                    conditions.join(" AND "),
+                    sort.order_by(),
+                ))?
+                .query_map::<Fish, _, _>(
+                    named_params_ref(&params).as_slice(),
+                    |row| row.try_into(),
+                )?
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+
+            let has_more =
+                limit.is_some_and(|limit| items.len() > limit as usize);
+            if let Some(limit) = limit {
+                items.truncate(limit as usize);
+            }
+            Ok(FishPage {
+                items,
+                total,
+                has_more,
+            })
+        })
+        .await
+    }
+
+    /// Stream every fish in this session, in ID order. Fish are read a page at
+    /// a time, so the whole set is never in memory. Each page is a separate
+    /// read, so a slow client doesn't hold a connection (or a lock) while it
+    /// catches up. The export isn't a snapshot: changes made while it's
+    /// running may or may not show up in it.
+    pub fn export(
+        &self,
+    ) -> impl Stream<Item = crate::Result<Fish>> + Send + use<> {
+        /// Where the next page starts, or `Done` after the last one
+        enum Position {
+            Start,
+            After(FishId),
+            Done,
+        }
+
+        let this = self.clone();
+        stream::try_unfold(Position::Start, move |position| {
+            let this = this.clone();
+            async move {
+                let after = match position {
+                    Position::Start => None,
+                    Position::After(id) => Some(id),
+                    Position::Done => return Ok::<_, Error>(None),
+                };
+                let page: Vec<Fish> = this
+                    .read(move |this, conn| {
+                        let fish = conn
+                            .prepare(
+                                "SELECT * FROM fish WHERE
+                                (session_id IS NULL AND :session_id IS NULL
+                                OR session_id = :session_id)
+                                AND (:after IS NULL OR id > :after)
+                                ORDER BY id LIMIT :limit",
+                            )?
+                            .query_map(
+                                named_params! {
+                                    ":session_id": this.session_id,
+                                    ":after": after,
+                                    ":limit": EXPORT_PAGE_SIZE,
+                                },
+                                |row| Fish::try_from(row),
+                            )?
+                            .collect::<Result<_, _>>()?;
+                        Ok(fish)
+                    })
+                    .await?;
+                let next = match page.last() {
+                    Some(fish) if page.len() == EXPORT_PAGE_SIZE => {
+                        Position::After(fish.id)
+                    }
+                    _ => Position::Done,
+                };
+                Ok(Some((stream::iter(page.into_iter().map(Ok)), next)))
+            }
+        })
+        .try_flatten()
     }
 
     /// Get a fish by ID for this session. Return `None` if not found
     pub async fn get(&self, id: FishId) -> crate::Result<Fish> {
-        let conn = self.connection().await;
-        let fish = conn.query_one(
-            "SELECT * FROM fish WHERE
+        self.read(move |this, conn| {
+            let fish = conn.query_one(
+                "SELECT * FROM fish WHERE
                 (session_id IS NULL AND :session_id IS NULL
                 OR session_id = :session_id)
                 AND id = :id",
-            named_params! { ":session_id": self.session_id, ":id": id },
+                named_params! { ":session_id": this.session_id, ":id": id },
+                |row| row.try_into(),
+            )?;
+            Ok(fish)
+        })
+        .await
+    }
+
+    /// Create a fish for this session
+    pub async fn create(&self, body: CreateFishRequest) -> crate::Result<Fish> {
+        self.write(move |this, conn| {
+            let fish = this.insert(conn, body)?;
+            this.publish(EventKind::Created(fish.clone()))?;
+            Ok(fish)
+        })
+        .await
+    }
+
+    /// Modify a fish by ID for this session. Return the modified fish or `None`
+    /// if not found. The modification is only made if the fish passes the
+    /// preconditions.
+    pub async fn update(
+        &self,
+        id: FishId,
+        body: UpdateFishRequest,
+        conditions: &Conditions,
+    ) -> crate::Result<Fish> {
+        let conditions = conditions.clone();
+        self.write(move |this, conn| {
+            // Writes are serialized, so nothing can change between check and
+            // update
+            this.get_checked(conn, id, &conditions)?;
+            let fish = this.update_fields(conn, id, body)?;
+            this.publish(EventKind::Updated(fish.clone()))?;
+            Ok(fish)
+        })
+        .await
+    }
+
+    /// Replace all fields of a fish by ID for this session. If the fish doesn't
+    /// exist, create it with the given ID. Return the fish, and whether it was
+    /// created.
+    pub async fn replace(
+        &self,
+        id: FishId,
+        body: CreateFishRequest,
+        conditions: &Conditions,
+    ) -> crate::Result<(Fish, bool)> {
+        let conditions = conditions.clone();
+        self.write(move |this, conn| match this.get_mut(conn, id) {
+            Ok(fish) => {
+                conditions.check_preconditions(&fish)?;
+                let fish = this.overwrite(conn, id, body)?;
+                this.publish(EventKind::Updated(fish.clone()))?;
+                Ok((fish, false))
+            }
+            Err(Error::NotFound) => {
+                conditions.check_create_preconditions()?;
+                if id.0 > MAX_CHOSEN_ID {
+                    return Err(Error::InvalidPath(format!(
+                        "New fish IDs must be at most {MAX_CHOSEN_ID}"
+                    )));
+                }
+                let fish: Fish = conn.query_one(
+                    "INSERT INTO fish
+                            (id, session_id, name, species, age, weight_kg)
+                        VALUES
+                            (:id, :session_id, :name, :species, :age,
+                            :weight_kg)
+                        RETURNING *",
+                    named_params! {
+                        ":id": id,
+                        ":session_id": this.session_id()?,
+                        ":name": body.name,
+                        ":species": body.species,
+                        ":age": body.age,
+                        ":weight_kg": body.weight_kg,
+                    },
+                    |row| row.try_into(),
+                )?;
+                this.publish(EventKind::Created(fish.clone()))?;
+                Ok((fish, true))
+            }
+            Err(error) => Err(error),
+        })
+        .await
+    }
+
+    /// Modify a fish by computing its new fields from its current state. The
+    /// whole operation is atomic, so the fish can't change between reading
+    /// and writing.
+    pub async fn modify(
+        &self,
+        id: FishId,
+        conditions: &Conditions,
+        f: impl 'static + FnOnce(&Fish) -> crate::Result<CreateFishRequest> + Send,
+    ) -> crate::Result<Fish> {
+        let conditions = conditions.clone();
+        self.write(move |this, conn| {
+            let fish = this.get_checked(conn, id, &conditions)?;
+            let body = f(&fish)?;
+            let fish = this.overwrite(conn, id, body)?;
+            this.publish(EventKind::Updated(fish.clone()))?;
+            Ok(fish)
+        })
+        .await
+    }
+
+    /// Delete a fish by ID for this session. Return the deleted fish or `None`
+    /// if not found. The fish is only deleted if it passes the preconditions.
+    pub async fn delete(
+        &self,
+        id: FishId,
+        conditions: &Conditions,
+    ) -> crate::Result<Fish> {
+        let conditions = conditions.clone();
+        self.write(move |this, conn| {
+            this.get_checked(conn, id, &conditions)?;
+            let fish = this.remove(conn, id)?;
+            this.publish(EventKind::Deleted(fish.clone()))?;
+            Ok(fish)
+        })
+        .await
+    }
+
+    /// Apply a batch of operations in a single transaction. Operations that
+    /// already failed (e.g. validation) are passed in as errors, so the
+    /// results line up with the request. In atomic mode, the first failure
+    /// rolls back the whole batch and is returned as the error. Otherwise,
+    /// failed operations are skipped and the rest are committed.
+    pub async fn bulk(
+        &self,
+        operations: Vec<crate::Result<BulkOperation>>,
+        atomic: bool,
+    ) -> crate::Result<Vec<crate::Result<Fish>>> {
+        self.write(move |this, conn| {
+            let transaction = conn.unchecked_transaction()?;
+            let mut results = Vec::with_capacity(operations.len());
+            let mut events = Vec::new();
+            for (index, operation) in operations.into_iter().enumerate() {
+                // Each operation is a single statement, so a failed one
+                // doesn't leave anything behind to roll back
+                let result = operation
+                    .and_then(|operation| this.apply(&transaction, operation));
+                match result {
+                    Ok(event) => {
+                        let fish =
+                            event.fish().expect("Operations change a fish");
+                        results.push(Ok(fish.clone()));
+                        events.push(event);
+                    }
+                    Err(error) if atomic => {
+                        return Err(Error::BulkOperation {
+                            index,
+                            source: Box::new(error),
+                        });
+                    }
+                    Err(error) => results.push(Err(error)),
+                }
+            }
+            transaction.commit()?;
+
+            // Only notify once the changes are committed
+            for event in events {
+                this.publish(event)?;
+            }
+            Ok(results)
+        })
+        .await
+    }
+
+    /// Get the image for a fish by ID for this session
+    pub async fn get_image(&self, id: FishId) -> crate::Result<Image> {
+        self.read(move |this, conn| {
+            let image = conn.query_one(
+                "SELECT image.* FROM image
+                JOIN fish ON image.fish_key = fish.key
+                WHERE (fish.session_id IS NULL AND :session_id IS NULL
+                    OR fish.session_id = :session_id)
+                AND fish.id = :id",
+                named_params! { ":session_id": this.session_id, ":id": id },
+                |row| row.try_into(),
+            )?;
+            Ok(image)
+        })
+        .await
+    }
+
+    /// Set the image for a fish by ID for this session, replacing the existing
+    /// image if any
+    pub async fn set_image(
+        &self,
+        id: FishId,
+        image: Image,
+    ) -> crate::Result<()> {
+        self.write(move |this, conn| this.attach_image(conn, id, image))
+            .await
+    }
+
+    /// Set the image for a fish in this session, replacing the existing image
+    /// if any. This takes the connection so it can be used within another
+    /// operation.
+    fn attach_image(
+        &self,
+        conn: &Connection,
+        id: FishId,
+        image: Image,
+    ) -> crate::Result<()> {
+        // Replacing an image doesn't add to the count
+        let others: usize = conn.query_one(
+            "SELECT count(*) FROM image
+            JOIN fish ON image.fish_key = fish.key
+            WHERE fish.session_id = :session_id AND fish.id != :id",
+            named_params! { ":session_id": self.session_id()?, ":id": id },
+            |row| row.get(0),
+        )?;
+        if others >= MAX_SESSION_IMAGES {
+            return Err(Error::TooManyImages {
+                max: MAX_SESSION_IMAGES,
+            });
+        }
+
+        // Select from the fish table to ensure the fish is in this session.
+        // If not, nothing will be inserted and we'll get a 404
+        conn.query_one(
+            "INSERT INTO image (fish_key, content_type, data)
+            SELECT key, :content_type, :data FROM fish
+                WHERE session_id = :session_id AND id = :id
+            ON CONFLICT (fish_key) DO UPDATE SET
+                content_type = excluded.content_type,
+                data = excluded.data
+            RETURNING fish_key",
+            named_params! {
+                ":session_id": self.session_id()?,
+                ":id": id,
+                ":content_type": image.format.to_mime_type(),
+                ":data": image.data,
+            },
+            |_| Ok(()),
+        )?;
+        Ok(())
+    }
+
+    /// Get a fish that can be modified by this session. Unlike [Self::get],
+    /// this excludes the default fish. This takes the connection so it can be
+    /// used within another operation.
+    fn get_mut(&self, conn: &Connection, id: FishId) -> crate::Result<Fish> {
+        let fish = conn.query_one(
+            "SELECT * FROM fish WHERE session_id = :session_id AND id = :id",
+            named_params! { ":session_id": self.session_id()?, ":id": id },
             |row| row.try_into(),
         )?;
         Ok(fish)
     }
 
-    /// Create a fish for this session
-    pub async fn create(&self, body: CreateFishRequest) -> crate::Result<Fish> {
-        let conn = self.connection().await;
+    /// Get a fish to modify, and check that it passes the preconditions. A
+    /// missing fish fails `If-Match` before it's reported as not found.
+    fn get_checked(
+        &self,
+        conn: &Connection,
+        id: FishId,
+        conditions: &Conditions,
+    ) -> crate::Result<Fish> {
+        match self.get_mut(conn, id) {
+            Ok(fish) => {
+                conditions.check_preconditions(&fish)?;
+                Ok(fish)
+            }
+            Err(Error::NotFound) => {
+                conditions.check_create_preconditions()?;
+                Err(Error::NotFound)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Apply one operation of a bulk request. Return the event to publish for
+    /// it once the transaction is committed
+    fn apply(
+        &self,
+        conn: &Connection,
+        operation: BulkOperation,
+    ) -> crate::Result<EventKind> {
+        match operation {
+            BulkOperation::Create { fish } => {
+                Ok(EventKind::Created(self.insert(conn, fish)?))
+            }
+            BulkOperation::Update { id, fish } => {
+                Ok(EventKind::Updated(self.update_fields(conn, id, fish)?))
+            }
+            BulkOperation::Delete { id } => {
+                Ok(EventKind::Deleted(self.remove(conn, id)?))
+            }
+        }
+    }
+
+    /// Insert a new fish, with the ID after the highest in the session
+    fn insert(
+        &self,
+        conn: &Connection,
+        body: CreateFishRequest,
+    ) -> crate::Result<Fish> {
         let fish = conn.query_one(
-            "INSERT INTO fish (session_id, name, species, age, weight_kg)
-            VALUES (:session_id, :name, :species, :age, :weight_kg)
+            "INSERT INTO fish (id, session_id, name, species, age, weight_kg)
+            VALUES (
+                (SELECT coalesce(max(id), 0) + 1 FROM fish
+                    WHERE session_id = :session_id),
+                :session_id, :name, :species, :age, :weight_kg
+            )
             RETURNING *",
             named_params! {
                 ":session_id": self.session_id()?,
@@ -242,14 +1341,13 @@ impl SessionStore {
         Ok(fish)
     }
 
-    /// Modify a fish by ID for this session. Return the modified fish or `None`
-    /// if not found
-    pub async fn update(
+    /// Update the given fields of an existing fish
+    fn update_fields(
         &self,
+        conn: &Connection,
         id: FishId,
         body: UpdateFishRequest,
     ) -> crate::Result<Fish> {
-        let conn = self.connection().await;
         let fish = conn.query_one(
             // If any given field is None, we'll update it to its existing
             // value. This only works for non-nullable columns
@@ -257,11 +1355,14 @@ impl SessionStore {
                 name = coalesce(:name, name),
                 species = coalesce(:species, species),
                 age = coalesce(:age, age),
-                weight_kg = coalesce(:weight_kg, weight_kg)
+                weight_kg = coalesce(:weight_kg, weight_kg),
+                version = version + 1,
+                updated_at = :now
             WHERE session_id = :session_id AND id = :id RETURNING *",
             named_params! {
                 ":session_id": self.session_id()?,
                 ":id": id,
+                ":now": Timestamp::now(),
                 ":name": body.name,
                 ":species": body.species,
                 ":age": body.age,
@@ -272,10 +1373,8 @@ impl SessionStore {
         Ok(fish)
     }
 
-    /// Delete a fish by ID for this session. Return the deleted fish or `None`
-    /// if not found
-    pub async fn delete(&self, id: FishId) -> crate::Result<Fish> {
-        let conn = self.connection().await;
+    /// Delete an existing fish
+    fn remove(&self, conn: &Connection, id: FishId) -> crate::Result<Fish> {
         let fish = conn.query_one(
             "DELETE FROM fish WHERE session_id = :session_id AND id = :id
             RETURNING *",
@@ -285,8 +1384,89 @@ impl SessionStore {
         Ok(fish)
     }
 
-    async fn connection(&self) -> impl Deref<Target = Connection> {
-        self.store.connection.lock().await
+    /// Overwrite all fields of an existing fish
+    fn overwrite(
+        &self,
+        conn: &Connection,
+        id: FishId,
+        body: CreateFishRequest,
+    ) -> crate::Result<Fish> {
+        let fish = conn.query_one(
+            "UPDATE fish SET
+                name = :name,
+                species = :species,
+                age = :age,
+                weight_kg = :weight_kg,
+                version = version + 1,
+                updated_at = :now
+            WHERE session_id = :session_id AND id = :id RETURNING *",
+            named_params! {
+                ":session_id": self.session_id()?,
+                ":id": id,
+                ":now": Timestamp::now(),
+                ":name": body.name,
+                ":species": body.species,
+                ":age": body.age,
+                ":weight_kg": body.weight_kg,
+            },
+            |row| row.try_into(),
+        )?;
+        Ok(fish)
+    }
+
+    /// Subscribe to changes in this session. See [EventHub::subscribe]
+    pub fn subscribe(
+        &self,
+        last_event_id: Option<u64>,
+    ) -> crate::Result<impl Stream<Item = Event> + Send + use<>> {
+        Ok(self
+            .store
+            .events
+            .subscribe(self.session_id()?, last_event_id))
+    }
+
+    /// Notify subscribers of a change in this session
+    fn publish(&self, kind: EventKind) -> crate::Result<()> {
+        self.store.events.publish(self.session_id()?, kind);
+        Ok(())
+    }
+
+    /// Run a query on a read-only connection
+    async fn read<T: 'static + Send>(
+        &self,
+        f: impl 'static + FnOnce(&Self, &Connection) -> crate::Result<T> + Send,
+    ) -> crate::Result<T> {
+        let this = self.clone();
+        self.store.readers.run(move |conn| f(&this, conn)).await
+    }
+
+    /// Run a mutation on the writer connection. Writes are serialized, so
+    /// nothing else can change the DB until `f` returns.
+    async fn write<T: 'static + Send>(
+        &self,
+        f: impl 'static + FnOnce(&Self, &Connection) -> crate::Result<T> + Send,
+    ) -> crate::Result<T> {
+        let this = self.clone();
+        self.store.writer.run(move |conn| f(&this, conn)).await
+    }
+
+    /// Get this session's lifetime and the scopes it can pass on. The scopes
+    /// are the ones that both the session and the request have: a session
+    /// token keeps its scopes even if the session is narrowed later.
+    fn grant(&self, conn: &Connection) -> crate::Result<(Duration, Scopes)> {
+        let session_id = self.session_id()?;
+        let (ttl_seconds, scope): (u64, Scopes) = conn
+            .query_one(
+                "SELECT ttl_seconds, scope FROM session WHERE id = :id",
+                named_params! { ":id": session_id },
+                |row| Ok((row.get("ttl_seconds")?, row.get("scope")?)),
+            )
+            .optional()?
+            .ok_or_else(|| session_id.not_found())?;
+        Ok((
+            Duration::from_secs(ttl_seconds),
+            scope.intersection(self.scope),
+        ))
     }
 
     /// Get the current session ID for a mutation. Return an error if there is
@@ -303,31 +1483,31 @@ impl<S: Send + Sync> FromRequestParts<S> for SessionStore {
         parts: &mut Parts,
         _: &S,
     ) -> Result<Self, Self::Rejection> {
-        async fn get_session_id(
-            parts: &Parts,
+        async fn get_session(
+            parts: &mut Parts,
             store: &Store,
-        ) -> Result<Option<SessionId>, Error> {
-            // Pull the session ID from the auth header. If the header isn't
-            // present, it's just an unauthenticated request.
-            let Some(authorization) = &parts.headers.get(header::AUTHORIZATION)
-            else {
-                return Ok(None);
+        ) -> Result<(Option<SessionId>, Scopes), Error> {
+            let Extension(keys) = parts
+                .extract::<Extension<Keys>>()
+                .await
+                .expect("Keys extension is always present");
+            // Pull the session ID from the credentials. If there are none,
+            // it's just an unauthenticated request.
+            let session_id = match auth::credentials(parts, &keys)? {
+                None => return Ok((None, Scopes::anonymous())),
+                // Verified by the signature, so skip the DB
+                Some(Credentials::Token(claims)) => {
+                    return Ok((Some(claims.sub), claims.scope));
+                }
+                Some(Credentials::SessionId(session_id)) => {
+                    SessionId(session_id)
+                }
             };
-            let session_id = authorization
-                .to_str()
-                .ok() // Should be valid UTF-8
-                .and_then(|authorization| authorization.strip_prefix("Bearer "))
-                .ok_or(Error::InvalidAuthorization)?;
-
-            let session_id = SessionId(session_id.to_owned());
 
             // Verify the session is in the store
-            if store.contains_session(&session_id).await? {
-                Ok(Some(session_id))
-            } else {
-                Err(Error::SessionNotFound {
-                    session_id: session_id.0.into_bytes(),
-                })
+            match store.session_scope(&session_id).await? {
+                Some(scope) => Ok((Some(session_id), scope)),
+                None => Err(session_id.not_found()),
             }
         }
 
@@ -335,19 +1515,102 @@ impl<S: Send + Sync> FromRequestParts<S> for SessionStore {
             .extract::<Extension<Store>>()
             .await
             .map_err(IntoResponse::into_response)?;
-        let session_id = get_session_id(parts, &store)
+        let (session_id, scope) = get_session(parts, &store)
             .await
             .map_err(IntoResponse::into_response)?;
 
-        Ok(Self { store, session_id })
+        Ok(Self {
+            store,
+            session_id,
+            scope,
+        })
+    }
+}
+
+/// Default and maximum lifetimes for sessions
+#[derive(Clone, Copy, Debug)]
+pub struct SessionTtl {
+    /// Lifetime for sessions that don't request one
+    pub default: Duration,
+    /// Longest lifetime a session can request
+    pub max: Duration,
+}
+
+impl SessionTtl {
+    /// Get the lifetime for a session. Return an error if the requested
+    /// lifetime is out of bounds
+    fn resolve(&self, ttl_seconds: Option<u64>) -> crate::Result<Duration> {
+        let Some(ttl_seconds) = ttl_seconds else {
+            return Ok(self.default);
+        };
+        let max = self.max.as_secs();
+        let mut validator = Validator::default();
+        validator.ensure(
+            "ttl_seconds",
+            (1..=max).contains(&ttl_seconds),
+            &format!("must be between 1 and {max}"),
+        );
+        validator.finish()?;
+        Ok(Duration::from_secs(ttl_seconds))
+    }
+}
+
+/// Metadata for a session
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: SessionId,
+    pub expires_at: Timestamp,
+    /// Lifetime of the session, used when refreshing it
+    pub ttl_seconds: u64,
+    /// Time until the session expires
+    pub remaining_seconds: u64,
+    /// Scopes granted to the session
+    pub scope: Scopes,
+    /// Signed session token, if requested when refreshing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/// Convert from `SELECT * FROM session`
+impl<'a, 'b> TryFrom<&'a Row<'b>> for SessionInfo {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &'a Row<'b>) -> Result<Self, Self::Error> {
+        let expires_at: Timestamp = row.get("expires_at")?;
+        let remaining = expires_at.duration_since(Timestamp::now());
+        Ok(Self {
+            id: row.get("id")?,
+            expires_at,
+            ttl_seconds: row.get("ttl_seconds")?,
+            remaining_seconds: remaining.as_secs().max(0) as u64,
+            scope: row.get("scope")?,
+            token: None,
+        })
     }
 }
 
 /// Unique ID for a user session, generated by `POST /login`
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct SessionId(String);
 
+impl SessionId {
+    /// Error for a session that isn't in the DB. A session token is verified
+    /// by its signature alone, so its session may have been logged out or
+    /// expired since it was issued.
+    fn not_found(&self) -> Error {
+        Error::SessionNotFound {
+            session_id: self.0.clone().into_bytes(),
+        }
+    }
+}
+
+impl Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 impl ToSql for SessionId {
     fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
         self.0.to_sql()
@@ -385,14 +1648,57 @@ impl FromSql for FishId {
     }
 }
 
+/// A pending OAuth authorization code grant, waiting to be exchanged for
+/// tokens
+#[derive(Debug)]
+pub struct AuthorizationGrant {
+    pub client_id: String,
+    pub redirect_uri: String,
+    /// PKCE challenge (RFC 7636)
+    pub code_challenge: String,
+    /// `S256` or `plain`
+    pub code_challenge_method: String,
+    pub scope: Scopes,
+}
+
+/// Convert from `SELECT * FROM oauth_code`
+impl<'a, 'b> TryFrom<&'a Row<'b>> for AuthorizationGrant {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &'a Row<'b>) -> Result<Self, Self::Error> {
+        Ok(Self {
+            client_id: row.get("client_id")?,
+            redirect_uri: row.get("redirect_uri")?,
+            code_challenge: row.get("code_challenge")?,
+            code_challenge_method: row.get("code_challenge_method")?,
+            scope: row.get("scope")?,
+        })
+    }
+}
+
+/// The grant behind an OAuth refresh token
+#[derive(Debug)]
+pub struct RefreshGrant {
+    pub session_id: SessionId,
+    pub client_id: String,
+}
+
 /// Just keep swimming swimming swimming...
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Fish {
+    /// Unique across every fish the DB has ever had, unlike the ID. This is
+    /// internal, and only used to tell fish with the same ID apart in ETags
+    #[serde(skip)]
+    pub key: i64,
     pub id: FishId,
     pub name: String,
     pub species: String,
     pub age: u32,
     pub weight_kg: f64,
+    /// Incremented on every modification. Used to generate the ETag
+    pub version: u32,
+    /// Time of the last modification
+    pub updated_at: Timestamp,
 }
 
 /// Convert from `SELECT * FROM fish`
@@ -401,15 +1707,390 @@ impl<'a, 'b> TryFrom<&'a Row<'b>> for Fish {
 
     fn try_from(row: &'a Row<'b>) -> Result<Self, Self::Error> {
         Ok(Self {
+            key: row.get("key")?,
             id: row.get("id")?,
             name: row.get("name")?,
             species: row.get("species")?,
             age: row.get("age")?,
             weight_kg: row.get("weight_kg")?,
+            version: row.get("version")?,
+            updated_at: row.get("updated_at")?,
         })
     }
 }
 
+/// An image attached to a fish. Only PNG and JPEG are supported
+#[derive(Clone, Debug)]
+pub struct Image {
+    pub format: ImageFormat,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    /// Supported image formats, in order of preference
+    pub const FORMATS: &[ImageFormat] = &[ImageFormat::Png, ImageFormat::Jpeg];
+
+    /// Load an image from raw bytes. The format is detected from the content
+    /// rather than trusted from the client. Return an error if the data isn't
+    /// a supported image format, or if it's corrupt. Decoding it up front
+    /// means it can always be converted later.
+    pub fn new(data: Vec<u8>) -> crate::Result<Self> {
+        let format = image::guess_format(&data)
+            .ok()
+            .filter(|format| Self::FORMATS.contains(format))
+            .ok_or(Error::UnsupportedImage)?;
+        image::load_from_memory_with_format(&data, format)
+            .map_err(Error::InvalidImage)?;
+        Ok(Self { format, data })
+    }
+
+    /// Convert this image to another format. If it's already in the requested
+    /// format, return it unchanged.
+    pub fn convert(self, format: ImageFormat) -> crate::Result<Self> {
+        if format == self.format {
+            return Ok(self);
+        }
+        let decoded =
+            image::load_from_memory_with_format(&self.data, self.format)?;
+        // JPEG doesn't support transparency, so drop the alpha channel
+        let decoded = DynamicImage::ImageRgb8(decoded.to_rgb8());
+        let mut data = Vec::new();
+        decoded.write_to(&mut io::Cursor::new(&mut data), format)?;
+        Ok(Self { format, data })
+    }
+}
+
+/// Serialized as the base64-encoded image data. The format is implied by the
+/// data.
+impl Serialize for Image {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64_STANDARD.encode(&self.data))
+    }
+}
+
+impl<'de> Deserialize<'de> for Image {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let data =
+            BASE64_STANDARD.decode(encoded).map_err(de::Error::custom)?;
+        Self::new(data).map_err(de::Error::custom)
+    }
+}
+
+/// Convert from `SELECT * FROM image`
+impl<'a, 'b> TryFrom<&'a Row<'b>> for Image {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &'a Row<'b>) -> Result<Self, Self::Error> {
+        let content_type: String = row.get("content_type")?;
+        let format =
+            ImageFormat::from_mime_type(&content_type).ok_or_else(|| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    1,
+                    Type::Text,
+                    format!("Unknown image type `{content_type}`").into(),
+                )
+            })?;
+        Ok(Self {
+            format,
+            data: row.get("data")?,
+        })
+    }
+}
+
+/// One page of results from [SessionStore::list]
+#[derive(Debug)]
+pub struct FishPage {
+    /// Fish in this page
+    pub items: Vec<Fish>,
+    /// Total number of fish matching the filters, across all pages
+    pub total: u64,
+    /// Is there at least one more fish after this page?
+    pub has_more: bool,
+}
+
+/// A sortable field of [Fish]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SortField {
+    Id,
+    Name,
+    Species,
+    Age,
+    WeightKg,
+}
+
+impl SortField {
+    /// Name of the field in both JSON and SQL
+    fn name(self) -> &'static str {
+        match self {
+            Self::Id => "id",
+            Self::Name => "name",
+            Self::Species => "species",
+            Self::Age => "age",
+            Self::WeightKg => "weight_kg",
+        }
+    }
+
+    /// Get this field's value from a fish
+    fn get(self, fish: &Fish) -> serde_json::Value {
+        match self {
+            Self::Id => fish.id.0.into(),
+            Self::Name => fish.name.as_str().into(),
+            Self::Species => fish.species.as_str().into(),
+            Self::Age => fish.age.into(),
+            Self::WeightKg => fish.weight_kg.into(),
+        }
+    }
+}
+
+/// One component of a sort order
+#[derive(Copy, Clone, Debug)]
+pub struct SortKey {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+/// Sort order for a list of fish, e.g. `age,-weight_kg`. Fields are separated
+/// by commas, and a leading `-` sorts that field in descending order.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Sort(Vec<SortKey>);
+
+impl Sort {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Append the ID as a final sort key if it isn't already included, so that
+    /// the order is total and cursors are unambiguous
+    fn with_tiebreaker(&self) -> Self {
+        let mut keys = self.0.clone();
+        if !keys.iter().any(|key| key.field == SortField::Id) {
+            keys.push(SortKey {
+                field: SortField::Id,
+                descending: false,
+            });
+        }
+        Self(keys)
+    }
+
+    fn order_by(&self) -> String {
+        self.0
+            .iter()
+            .map(|key| {
+                let direction = if key.descending { "DESC" } else { "ASC" };
+                format!("{} {direction}", key.field.name())
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl TryFrom<String> for Sort {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(|key| {
+                let (name, descending) = match key.strip_prefix('-') {
+                    Some(name) => (name, true),
+                    None => (key.strip_prefix('+').unwrap_or(key), false),
+                };
+                let field = match name {
+                    "id" => SortField::Id,
+                    "name" => SortField::Name,
+                    "species" => SortField::Species,
+                    "age" => SortField::Age,
+                    "weight_kg" => SortField::WeightKg,
+                    _ => return Err(format!("Unknown sort field `{name}`")),
+                };
+                Ok(SortKey { field, descending })
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl From<Sort> for String {
+    fn from(sort: Sort) -> Self {
+        sort.to_string()
+    }
+}
+
+impl Display for Sort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, key) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            if key.descending {
+                write!(f, "-")?;
+            }
+            write!(f, "{}", key.field.name())?;
+        }
+        Ok(())
+    }
+}
+
+/// An opaque position in a sorted list of fish, used for keyset pagination.
+/// The cursor stores the sort key values of the last fish in a page, and the
+/// next page starts with the first fish that sorts after those values.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cursor {
+    /// Sort order the cursor was created for. Cursors can't be reused across
+    /// different sorts, because the values wouldn't line up
+    sort: String,
+    /// Values of each sort key for the last fish in the previous page
+    after: Vec<serde_json::Value>,
+}
+
+impl Cursor {
+    /// Create a cursor pointing after the given fish
+    pub fn after(sort: &Sort, fish: &Fish) -> Self {
+        let sort = sort.with_tiebreaker();
+        Self {
+            sort: sort.to_string(),
+            after: sort.0.iter().map(|key| key.field.get(fish)).collect(),
+        }
+    }
+
+    /// Build a WHERE condition that selects fish after this cursor. For sort
+    /// keys (a, b, c), this generates:
+    ///
+    /// `a > :a OR (a = :a AND b > :b) OR (a = :a AND b = :b AND c > :c)`
+    ///
+    /// where `>` is flipped to `<` for descending keys.
+    fn condition(
+        &self,
+        sort: &Sort,
+    ) -> crate::Result<(String, Vec<(String, Value)>)> {
+        if self.sort != sort.to_string() || self.after.len() != sort.0.len() {
+            return Err(Error::InvalidCursor);
+        }
+
+        let params = self
+            .after
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let value = match value {
+                    serde_json::Value::String(s) => Value::Text(s.clone()),
+                    serde_json::Value::Number(n) => n
+                        .as_i64()
+                        .map(Value::Integer)
+                        .or_else(|| n.as_f64().map(Value::Real))
+                        .ok_or(Error::InvalidCursor)?,
+                    _ => return Err(Error::InvalidCursor),
+                };
+                Ok((format!(":cursor_{i}"), value))
+            })
+            .collect::<crate::Result<Vec<_>>>()?;
+
+        let clauses: Vec<String> = sort
+            .0
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                let operator = if key.descending { "<" } else { ">" };
+                let equal = sort.0[..i].iter().enumerate().map(|(j, key)| {
+                    format!("{} = :cursor_{j}", key.field.name())
+                });
+                let clause = equal
+                    .chain([format!(
+                        "{} {operator} :cursor_{i}",
+                        key.field.name()
+                    )])
+                    .collect::<Vec<_>>()
+                    .join(" AND ");
+                format!("({clause})")
+            })
+            .collect();
+        Ok((format!("({})", clauses.join(" OR ")), params))
+    }
+}
+
+impl TryFrom<String> for Cursor {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let json = URL_SAFE_NO_PAD
+            .decode(value)
+            .map_err(|_| Error::InvalidCursor)?;
+        #[derive(Deserialize)]
+        struct Raw {
+            sort: String,
+            after: Vec<serde_json::Value>,
+        }
+        let raw: Raw =
+            serde_json::from_slice(&json).map_err(|_| Error::InvalidCursor)?;
+        Ok(Self {
+            sort: raw.sort,
+            after: raw.after,
+        })
+    }
+}
+
+impl From<Cursor> for String {
+    fn from(cursor: Cursor) -> Self {
+        let json = serde_json::json!({
+            "sort": cursor.sort,
+            "after": cursor.after,
+        });
+        URL_SAFE_NO_PAD.encode(json.to_string())
+    }
+}
+
+/// Convert owned named parameters to the borrowed form rusqlite wants
+fn named_params_ref(params: &[(String, Value)]) -> Vec<(&str, &dyn ToSql)> {
+    params
+        .iter()
+        .map(|(name, value)| (name.as_str(), value as &dyn ToSql))
+        .collect()
+}
+
+/// Generate a deterministic set of random fish. The RNG is seeded with a
+/// constant so the same fish are generated every time.
+fn generate_fish(count: usize) -> Vec<StaticFish> {
+    const NAMES: &[&str] = &[
+        "Bubbles", "Finn", "Goldie", "Gill", "Marlin", "Coral", "Splash",
+        "Flounder", "Wanda", "Jaws", "Sushi", "Captain", "Squirt", "Gurgle",
+        "Peach", "Deb", "Bloat", "Jacques", "Crush", "Pearl",
+    ];
+    const SPECIES: &[&str] = &[
+        "Clownfish",
+        "Blue Tang",
+        "Sockeye Salmon",
+        "Great Barracuda",
+        "Goldfish",
+        "Angelfish",
+        "Pufferfish",
+        "Rainbow Trout",
+        "Atlantic Cod",
+        "Yellowfin Tuna",
+        "Moorish Idol",
+        "Royal Gramma",
+    ];
+
+    let mut rng = StdRng::seed_from_u64(0x0051_40a1);
+    (0..count)
+        .map(|_| StaticFish {
+            name: NAMES.choose(&mut rng).unwrap(),
+            species: SPECIES.choose(&mut rng).unwrap(),
+            age: rng.random_range(0..=20),
+            weight_kg: (rng.random_range(0.1..50.0_f64) * 10.0).round() / 10.0,
+        })
+        .collect()
+}
+
 /// Fish defined in static code
 struct StaticFish {
     name: &'static str,
@@ -417,3 +2098,155 @@ struct StaticFish {
     age: u32,
     weight_kg: f64,
 }
+
+/// Fixtures shared by tests across modules
+#[cfg(test)]
+impl Store {
+    /// Build an in-memory store, as the server does by default
+    pub fn test(generated_fish: usize) -> Self {
+        let session_ttl = SessionTtl {
+            default: Duration::from_secs(60),
+            max: Duration::from_secs(60),
+        };
+        Self::new(None, generated_fish, session_ttl).unwrap()
+    }
+
+    /// Log in to a new session, and get a view of the store for it limited
+    /// to the given scopes, as a request for that session would see it
+    pub async fn test_session(&self, scope: Scopes) -> SessionStore {
+        let login = self.create_session(None, scope).await.unwrap();
+        SessionStore {
+            store: self.clone(),
+            session_id: Some(login.id),
+            scope,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{conditional::etag, format::Format};
+    use futures::StreamExt;
+
+    fn new_fish(name: &str) -> CreateFishRequest {
+        CreateFishRequest {
+            name: name.to_owned(),
+            species: "Cod".to_owned(),
+            age: 1,
+            weight_kg: 1.0,
+        }
+    }
+
+    /// Hammer [SessionStore::list] from many sessions at once while other
+    /// sessions write. Reads and writes contend for the same DB, so this
+    /// catches anything that holds a lock for too long: a blocked writer
+    /// gives up after [BUSY_TIMEOUT] with "database is locked".
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn list_concurrently() {
+        const READERS: usize = 32;
+        const WRITERS: usize = 4;
+        const REQUESTS: usize = 50;
+
+        let store = Store::test(1000);
+        let mut tasks = Vec::new();
+        for _ in 0..READERS {
+            let session = store.test_session(Scopes::all()).await;
+            tasks.push(tokio::spawn(async move {
+                let query = ListFishQuery {
+                    limit: Some(20),
+                    ..ListFishQuery::default()
+                };
+                for _ in 0..REQUESTS {
+                    let page = session.list(&query).await.unwrap();
+                    assert_eq!(page.items.len(), 20);
+                    assert_eq!(page.total, 1004);
+                }
+            }));
+        }
+        for _ in 0..WRITERS {
+            let session = store.test_session(Scopes::all()).await;
+            tasks.push(tokio::spawn(async move {
+                for i in 0..REQUESTS {
+                    let name = format!("Fish {i}");
+                    if let Err(error) = session.create(new_fish(&name)).await {
+                        panic!("Write {i} failed: {error}");
+                    }
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+    }
+
+    /// A new fish that takes a deleted fish's ID still gets a new ETag, so
+    /// clients can't mistake one for the other
+    #[tokio::test]
+    async fn etag_after_id_reuse() {
+        let store = Store::test(0);
+        let session = store.test_session(Scopes::all()).await;
+        let conditions = Conditions::default();
+        let first = session.create(new_fish("First")).await.unwrap();
+        session.delete(first.id, &conditions).await.unwrap();
+        let second = session.create(new_fish("Second")).await.unwrap();
+        assert_eq!(first.id, second.id);
+        assert_ne!(etag(&first, Format::Json), etag(&second, Format::Json));
+    }
+
+    /// Event IDs restart with the server, so a client can reconnect with an
+    /// ID that hasn't been reached yet. It should get new events right away,
+    /// rather than once the IDs catch up.
+    #[tokio::test]
+    async fn subscribe_after_future_event_id() {
+        let store = Store::test(0);
+        let session = store.test_session(Scopes::all()).await;
+        let mut events = Box::pin(session.subscribe(Some(1000)).unwrap());
+        let fish = session.create(new_fish("New")).await.unwrap();
+        let event = tokio::time::timeout(Duration::from_secs(5), events.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.id, 1);
+        let EventKind::Created(created) = &event.kind else {
+            panic!("Expected a created event, got {event:?}");
+        };
+        assert_eq!(created.id, fish.id);
+    }
+
+    /// An export that the client stops reading mustn't hold anything the
+    /// rest of the server needs. Stall more exports than there are reader
+    /// connections, then check that other sessions can still read and write.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn export_stalled() {
+        let store = Store::test(1000);
+        let mut exports = Vec::new();
+        for _ in 0..READER_COUNT * 2 {
+            let mut export =
+                Box::pin(store.test_session(Scopes::all()).await.export());
+            let fish = export.next().await.unwrap().unwrap();
+            assert_eq!(fish.id, FishId(1));
+            exports.push(export);
+        }
+
+        // A blocked writer fails with "database is locked" after
+        // BUSY_TIMEOUT. The timeout only catches a read waiting forever for
+        // a connection, so it's well past that.
+        let other = store.test_session(Scopes::all()).await;
+        let result = tokio::time::timeout(BUSY_TIMEOUT * 4, async {
+            other.create(new_fish("Writer")).await?;
+            other.list(&ListFishQuery::default()).await
+        })
+        .await
+        .expect("Other session is stuck behind the stalled exports");
+        if let Err(error) = result {
+            panic!("Other session failed: {error}");
+        }
+
+        // The stalled exports pick up where they left off, across pages
+        let rest: Vec<Fish> =
+            exports.pop().unwrap().try_collect().await.unwrap();
+        assert_eq!(rest.len(), 1003);
+        assert!(rest.windows(2).all(|pair| pair[0].id.0 < pair[1].id.0));
+    }
+}
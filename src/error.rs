@@ -1,10 +1,23 @@
+use crate::{
+    auth, compression::Encoding, format::Format, problem::Problem,
+    rate_limit::ceil_seconds, routes::PATCH_TYPES, scope::Scope,
+    validate::FieldError,
+};
 use axum::{
     Json,
-    http::StatusCode,
+    extract::{
+        multipart::{MultipartError, MultipartRejection},
+        rejection::{
+            BytesRejection, FormRejection, JsonRejection, QueryRejection,
+        },
+    },
+    http::{HeaderValue, StatusCode, header},
     response::{IntoResponse, Response},
 };
+use image::ImageError;
+use json_patch::{PatchError, PatchErrorKind};
 use serde::Serialize;
-use std::io;
+use std::{error::Error as StdError, io, time::Duration};
 use thiserror::Error;
 use tracing::error;
 
@@ -13,19 +26,133 @@ pub type Result<T> = std::result::Result<T, Error>;
 /// Any error that can occur within the service
 #[derive(Debug, Error)]
 pub enum Error {
-    /// Authorization header was present but contained something other than
-    /// `Bearer <session>`
-    #[error("Invalid Authorization header. Expected `Bearer <session_id>`")]
+    /// Failed to read the request body
+    #[error(transparent)]
+    Body(#[from] BytesRejection),
+
+    /// An operation in an atomic bulk request failed, so none were applied
+    #[error("Operation {index} failed: {source}")]
+    BulkOperation {
+        /// Position of the failed operation in the request
+        index: usize,
+        source: Box<Error>,
+    },
+
+    /// Request body couldn't be deserialized as a form
+    #[error(transparent)]
+    Form(#[from] FormRejection),
+
+    /// User is authenticated, but their session wasn't granted the scope
+    /// needed for the operation
+    #[error("This operation requires the `{required}` scope")]
+    Forbidden { required: Scope },
+
+    /// Error decoding or encoding an image
+    #[error(transparent)]
+    Image(#[from] ImageError),
+
+    /// Authorization header was present but used an unsupported scheme, or
+    /// its credentials were malformed
+    #[error(
+        "Invalid Authorization header. Expected `Bearer`, `Basic`, or \
+        `Digest` credentials"
+    )]
     InvalidAuthorization,
 
+    /// Request body couldn't be decoded in the format given by its
+    /// `Content-Type`
+    #[error("Invalid {format} body: {message}")]
+    InvalidBody { format: Format, message: String },
+
+    /// Request body couldn't be decompressed according to its
+    /// `Content-Encoding`
+    #[error("Invalid {encoding} body: {message}")]
+    InvalidEncoding { encoding: Encoding, message: String },
+
+    /// Uploaded image was in a supported format, but couldn't be decoded
+    #[error("Invalid image: {0}")]
+    InvalidImage(ImageError),
+
+    /// Credentials were well-formed but didn't verify, e.g. a Digest response
+    /// that doesn't match
+    #[error("Invalid credentials")]
+    InvalidCredentials,
+
+    /// A bearer token was a JWT, but it couldn't be verified
+    #[error("Invalid token: {0}")]
+    InvalidToken(&'static str),
+
+    /// Path parameters are well-formed but have invalid values
+    #[error("Invalid path: {0}")]
+    InvalidPath(String),
+
+    /// Pagination cursor couldn't be decoded, or doesn't match the request
+    #[error("Invalid cursor")]
+    InvalidCursor,
+
+    /// Query parameters are well-formed but have invalid values
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+
     /// I/O error transmitting on the network
     #[error(transparent)]
     Io(#[from] io::Error),
 
+    /// Request body couldn't be deserialized as JSON
+    #[error(transparent)]
+    Json(#[from] JsonRejection),
+
+    /// Image upload was `multipart/form-data` but didn't contain any file
+    #[error("Multipart body must contain an `image` field")]
+    MissingImage,
+
+    /// Error reading a `multipart/form-data` body
+    #[error(transparent)]
+    Multipart(#[from] MultipartError),
+
+    /// Request body couldn't be read as `multipart/form-data`
+    #[error(transparent)]
+    MultipartRejection(#[from] MultipartRejection),
+
+    /// None of the media types in the `Accept` header can be produced
+    #[error("No acceptable content type. Available: {}", .available.join(", "))]
+    NotAcceptable {
+        /// Media types that the endpoint can produce
+        available: Vec<&'static str>,
+    },
+
     /// User requested a resource that doesn't exist
     #[error("Not found")]
     NotFound,
 
+    /// JSON Patch couldn't be applied
+    #[error("Patch operation {} failed: {}", .0.operation, .0.kind)]
+    Patch(#[from] PatchError),
+
+    /// Decompressed request body is larger than the limit
+    #[error("Request body is larger than {limit} bytes when decompressed")]
+    PayloadTooLarge { limit: usize },
+
+    /// A conditional request header (e.g. `If-Match`) didn't match the
+    /// current state of the resource
+    #[error("Precondition failed")]
+    PreconditionFailed,
+
+    /// Query parameters couldn't be deserialized
+    #[error(transparent)]
+    Query(#[from] QueryRejection),
+
+    /// `Range` header only covers bytes past the end of the resource
+    #[error("Range not satisfiable. The resource is {length} bytes")]
+    RangeNotSatisfiable { length: u64 },
+
+    /// Client has used up its rate limit
+    #[error("Rate limit exceeded. Retry in {} seconds", ceil_seconds(*.retry_after))]
+    RateLimited {
+        /// Time until the client can make another request
+        retry_after: Duration,
+    },
+
     /// User submitted a session ID that's either invalid or no longer in the
     /// DB
     #[error("Session `{}` not found", String::from_utf8_lossy(.session_id))]
@@ -40,34 +167,371 @@ pub enum Error {
     #[error(transparent)]
     Sqlite(rusqlite::Error),
 
-    /// Mutations not allowed because the user isn't authenticated
-    #[error("Mutations not allowed without an active session")]
+    /// Digest credentials were correct, but their nonce has expired
+    #[error("Digest nonce has expired. Retry with the new nonce")]
+    StaleNonce,
+
+    /// Session already has as many images as it's allowed
+    #[error("A session can have at most {max} images")]
+    TooManyImages { max: usize },
+
+    /// Database schema is newer than this version of the service knows about
+    #[error(
+        "Database schema version {version} is newer than the latest \
+        supported version {supported}"
+    )]
+    UnknownSchemaVersion { version: usize, supported: usize },
+
+    /// Operation requires a session, but the user isn't authenticated
+    #[error("This operation requires an active session")]
     Unauthenticated,
+
+    /// Request body's `Content-Encoding` isn't one of the supported codings
+    #[error(
+        "Unsupported content encoding `{0}`. Expected one of: {accepted}",
+        accepted = Encoding::names().join(", ")
+    )]
+    UnsupportedEncoding(String),
+
+    /// Uploaded image was not in a supported format
+    #[error("Unsupported image format. Expected `image/png` or `image/jpeg`")]
+    UnsupportedImage,
+
+    /// Request body's `Content-Type` isn't one of the supported formats
+    #[error(
+        "Unsupported content type. Expected one of: {}",
+        .accepted.join(", ")
+    )]
+    UnsupportedMediaType {
+        /// Media types that the endpoint accepts
+        accepted: Vec<&'static str>,
+    },
+
+    /// `PATCH` body was not one of the supported patch formats
+    #[error(
+        "Unsupported patch format. Expected one of: {}",
+        PATCH_TYPES.join(", ")
+    )]
+    UnsupportedPatch,
+
+    /// Request body was well-formed but one or more fields have invalid values
+    #[error("Invalid request body: {}", format_field_errors(.0))]
+    Validation(Vec<FieldError>),
+}
+
+impl Error {
+    /// HTTP status code for this error
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::InvalidAuthorization
+            | Self::InvalidCredentials
+            | Self::InvalidToken(_)
+            | Self::SessionNotFound { .. }
+            | Self::StaleNonce
+            | Self::Unauthenticated => StatusCode::UNAUTHORIZED,
+            Self::Forbidden { .. } => StatusCode::FORBIDDEN,
+            Self::InvalidBody { .. }
+            | Self::InvalidCursor
+            | Self::InvalidEncoding { .. }
+            | Self::InvalidPath(_)
+            | Self::InvalidQuery(_)
+            | Self::MissingImage => StatusCode::BAD_REQUEST,
+            Self::BulkOperation { source, .. } => source.status(),
+            Self::Query(rejection) => rejection.status(),
+            Self::Body(rejection) => rejection.status(),
+            Self::Multipart(error) => error.status(),
+            Self::MultipartRejection(rejection) => rejection.status(),
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::NotAcceptable { .. } => StatusCode::NOT_ACCEPTABLE,
+            Self::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            Self::RangeNotSatisfiable { .. } => {
+                StatusCode::RANGE_NOT_SATISFIABLE
+            }
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::InvalidImage(_) | Self::Validation(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            Self::Form(rejection) => rejection.status(),
+            Self::Json(rejection) => rejection.status(),
+            // RFC 5789 recommends 409 when the patch can't be applied to the
+            // current state of the resource
+            Self::Patch(PatchError {
+                kind: PatchErrorKind::TestFailed,
+                ..
+            }) => StatusCode::CONFLICT,
+            Self::Patch(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::TooManyImages { .. } => StatusCode::CONFLICT,
+            Self::UnsupportedEncoding(_)
+            | Self::UnsupportedMediaType { .. }
+            | Self::UnsupportedPatch => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::UnsupportedImage => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::Image(_)
+            | Self::Io(_)
+            | Self::Sqlite(_)
+            | Self::UnknownSchemaVersion { .. } => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// User-facing error message. Internal errors are hidden from the user
+    pub fn detail(&self) -> String {
+        if self.status().is_server_error() {
+            "Internal server error".to_owned()
+        } else {
+            self.to_string()
+        }
+    }
+
+    /// Log the error if it's internal, because [Self::detail] hides it from
+    /// the user. Anything that reports an error to the client should call this
+    pub fn log(&self) {
+        if self.status().is_server_error() {
+            error!("Internal server error: {self}");
+        }
+    }
+
+    /// Individual invalid fields in the request body, if known
+    pub fn field_errors(&self) -> Vec<FieldError> {
+        match self {
+            Self::Validation(errors) => errors.clone(),
+            // axum wraps the deserialization error, which knows the path to
+            // the offending field
+            Self::Json(JsonRejection::JsonDataError(rejection)) => rejection
+                .source()
+                .and_then(StdError::source)
+                .and_then(|error| {
+                    error.downcast_ref::<serde_path_to_error::Error<
+                        serde_json::Error,
+                    >>()
+                })
+                .map(FieldError::from_serde)
+                .into_iter()
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Problem type identifier and title, for problem details responses
+    fn problem_type(&self) -> (&'static str, &'static str) {
+        match self {
+            // The failed operation determines the type
+            Self::BulkOperation { source, .. } => source.problem_type(),
+            Self::Body(_) => {
+                ("unreadable-body", "Request body could not be read")
+            }
+            Self::Forbidden { .. } => {
+                ("insufficient-scope", "Insufficient scope")
+            }
+            Self::Image(_)
+            | Self::Io(_)
+            | Self::Sqlite(_)
+            | Self::UnknownSchemaVersion { .. } => {
+                ("internal", "Internal server error")
+            }
+            Self::InvalidAuthorization => {
+                ("invalid-authorization", "Invalid Authorization header")
+            }
+            Self::InvalidBody { .. } => {
+                ("invalid-body", "Request body could not be decoded")
+            }
+            Self::InvalidEncoding { .. } => {
+                ("invalid-encoding", "Request body could not be decompressed")
+            }
+            Self::InvalidCredentials => {
+                ("invalid-credentials", "Invalid credentials")
+            }
+            Self::InvalidImage(_) => ("invalid-image", "Invalid image"),
+            Self::InvalidCursor => ("invalid-cursor", "Invalid cursor"),
+            Self::InvalidPath(_) => ("invalid-path", "Invalid path parameters"),
+            Self::InvalidToken(_) => ("invalid-token", "Invalid token"),
+            Self::InvalidQuery(_) | Self::Query(_) => {
+                ("invalid-query", "Invalid query parameters")
+            }
+            Self::Form(_) => ("invalid-form", "Invalid form body"),
+            Self::Json(_) => ("invalid-json", "Invalid JSON body"),
+            Self::MissingImage => ("missing-image", "Missing image"),
+            Self::Multipart(_) | Self::MultipartRejection(_) => {
+                ("invalid-multipart", "Invalid multipart body")
+            }
+            Self::NotAcceptable { .. } => {
+                ("not-acceptable", "No acceptable content type")
+            }
+            Self::NotFound => ("not-found", "Not found"),
+            Self::Patch(_) => ("patch-failed", "Patch could not be applied"),
+            Self::PayloadTooLarge { .. } => {
+                ("payload-too-large", "Request body too large")
+            }
+            Self::PreconditionFailed => {
+                ("precondition-failed", "Precondition failed")
+            }
+            Self::RangeNotSatisfiable { .. } => {
+                ("range-not-satisfiable", "Range not satisfiable")
+            }
+            Self::RateLimited { .. } => ("rate-limited", "Too many requests"),
+            Self::SessionNotFound { .. } => {
+                ("session-not-found", "Session not found")
+            }
+            Self::StaleNonce => ("stale-nonce", "Digest nonce expired"),
+            Self::TooManyImages { .. } => {
+                ("too-many-images", "Too many images")
+            }
+            Self::Unauthenticated => {
+                ("unauthenticated", "Authentication required")
+            }
+            Self::UnsupportedEncoding(_) => {
+                ("unsupported-encoding", "Unsupported content encoding")
+            }
+            Self::UnsupportedImage => {
+                ("unsupported-image", "Unsupported image format")
+            }
+            Self::UnsupportedMediaType { .. } => {
+                ("unsupported-media-type", "Unsupported content type")
+            }
+            Self::UnsupportedPatch => {
+                ("unsupported-patch", "Unsupported patch format")
+            }
+            Self::Validation(_) => ("validation", "Invalid request body"),
+        }
+    }
+
+    /// Build a problem details object for this error, with any extension
+    /// members specific to its type
+    fn problem(&self, detail: String, errors: &[FieldError]) -> Problem {
+        let (kind, title) = self.problem_type();
+        let problem = Problem::new(kind, title, self.status().as_u16(), detail);
+        match self {
+            Self::BulkOperation { index, .. } => {
+                problem.extension("index", index)
+            }
+            Self::Forbidden { required } => {
+                problem.extension("scope", required.to_string())
+            }
+            Self::NotAcceptable { available } => {
+                problem.extension("available", available)
+            }
+            Self::RateLimited { retry_after } => {
+                problem.extension("retry_after", ceil_seconds(*retry_after))
+            }
+            Self::SessionNotFound { session_id } => problem
+                .extension("session_id", String::from_utf8_lossy(session_id)),
+            Self::PayloadTooLarge { limit } => {
+                problem.extension("limit", limit)
+            }
+            Self::UnsupportedEncoding(_) => {
+                problem.extension("accepted", Encoding::names())
+            }
+            Self::UnsupportedMediaType { accepted } => {
+                problem.extension("accepted", accepted)
+            }
+            Self::UnsupportedPatch => {
+                problem.extension("accepted", PATCH_TYPES)
+            }
+            _ if !errors.is_empty() => problem.extension("errors", errors),
+            _ => problem,
+        }
+    }
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        let (status_code, detail) = match self {
-            Self::InvalidAuthorization | Self::SessionNotFound { .. } => {
-                (StatusCode::BAD_REQUEST, None)
-            }
-            Self::Unauthenticated => (StatusCode::UNAUTHORIZED, None),
-            Self::NotFound => (StatusCode::NOT_FOUND, None),
-            Self::Io(_) | Self::Sqlite(_) => {
-                error!("Internal server error: {self}");
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Some("Internal server error".to_owned()),
-                )
-            }
-        };
-        (
-            status_code,
-            Json(ErrorDetail {
-                detail: detail.unwrap_or_else(|| self.to_string()),
-            }),
-        )
-            .into_response()
+        self.log();
+        let detail = self.detail();
+        let errors = self.field_errors();
+        // The legacy format is the default. The problem is attached so the
+        // problem details middleware can swap it in if the client wants it
+        let problem = self.problem(detail.clone(), &errors);
+        let mut response =
+            (self.status(), Json(ErrorDetail { detail, errors }))
+                .into_response();
+        response.extensions_mut().insert(problem);
+        let headers = response.headers_mut();
+        match self {
+            // RFC 9110 section 15.5.16
+            Self::UnsupportedEncoding(_) => {
+                headers.insert(
+                    header::ACCEPT_ENCODING,
+                    HeaderValue::from_str(&Encoding::names().join(", "))
+                        .expect("Encoding names are ASCII"),
+                );
+            }
+            Self::UnsupportedMediaType { accepted } => {
+                headers.insert(
+                    header::ACCEPT,
+                    HeaderValue::from_str(&accepted.join(", "))
+                        .expect("Media types are ASCII"),
+                );
+            }
+            Self::UnsupportedPatch => {
+                headers.insert(
+                    "Accept-Patch",
+                    HeaderValue::from_str(&PATCH_TYPES.join(", "))
+                        .expect("Patch types are ASCII"),
+                );
+            }
+            // Tell the client how to authenticate
+            Self::InvalidAuthorization
+            | Self::InvalidCredentials
+            | Self::InvalidToken(_)
+            | Self::SessionNotFound { .. }
+            | Self::Unauthenticated => {
+                for challenge in auth::challenges(false) {
+                    headers.append(header::WWW_AUTHENTICATE, challenge);
+                }
+            }
+            // Tell the client how big the resource is (RFC 9110 section
+            // 15.5.17)
+            Self::RangeNotSatisfiable { length } => {
+                headers.insert(
+                    header::CONTENT_RANGE,
+                    HeaderValue::from_str(&format!("bytes */{length}"))
+                        .expect("Content-Range is ASCII"),
+                );
+            }
+            Self::RateLimited { retry_after } => {
+                headers.insert(
+                    header::RETRY_AFTER,
+                    HeaderValue::from(ceil_seconds(retry_after)),
+                );
+            }
+            // Tell the client which scope it's missing (RFC 6750 section 3.1)
+            Self::Forbidden { required } => {
+                headers.insert(
+                    header::WWW_AUTHENTICATE,
+                    auth::insufficient_scope(required),
+                );
+            }
+            Self::StaleNonce => {
+                for challenge in auth::challenges(true) {
+                    headers.append(header::WWW_AUTHENTICATE, challenge);
+                }
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+/// GraphQL errors carry the same information as a problem details response,
+/// in the error's extensions
+impl From<Error> for async_graphql::Error {
+    fn from(error: Error) -> Self {
+        error.log();
+        let (kind, _) = error.problem_type();
+        let errors = error.field_errors();
+        let mut graphql_error = Self::new(error.detail());
+        let extensions = graphql_error.extensions.get_or_insert_default();
+        extensions.set("code", kind);
+        extensions.set("status", error.status().as_u16());
+        if !errors.is_empty() {
+            extensions.set(
+                "errors",
+                async_graphql::to_value(errors)
+                    .expect("Serialization is infallible"),
+            );
+        }
+        graphql_error
     }
 }
 
@@ -76,13 +540,33 @@ impl From<rusqlite::Error> for Error {
         match error {
             // Map empty query error to 404
             rusqlite::Error::QueryReturnedNoRows => Self::NotFound,
+            // Fish reference their session. Tokens are verified without
+            // checking the DB, so the session may be gone by the time we
+            // write to it
+            rusqlite::Error::SqliteFailure(error, _)
+                if error.extended_code
+                    == rusqlite::ffi::SQLITE_CONSTRAINT_FOREIGNKEY =>
+            {
+                Self::Unauthenticated
+            }
             _ => Self::Sqlite(error),
         }
     }
 }
 
-/// Body for error responses
+/// Body for error responses, unless the client asks for problem details
 #[derive(Debug, Serialize)]
 pub struct ErrorDetail {
     detail: String,
+    /// Invalid fields in the request body. Omitted if not applicable
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
+}
+
+fn format_field_errors(errors: &[FieldError]) -> String {
+    errors
+        .iter()
+        .map(|error| format!("{}: {}", error.field, error.reason))
+        .collect::<Vec<_>>()
+        .join(", ")
 }
@@ -0,0 +1,213 @@
+//! Per-session notifications of changes to fish
+
+use crate::data::{Fish, SessionId};
+use futures::{Stream, stream};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Maximum number of past events to retain per session for replay
+const HISTORY_SIZE: usize = 100;
+
+/// Broadcasts fish changes to all subscribers within a session. Each session
+/// keeps a bounded history of its recent events, so that clients that
+/// disconnect can resume where they left off. This uses an Arc so it is safe
+/// and cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct EventHub {
+    sessions: Arc<Mutex<HashMap<SessionId, SessionEvents>>>,
+}
+
+impl EventHub {
+    /// Notify all subscribers in a session of a change
+    pub fn publish(&self, session_id: &SessionId, kind: EventKind) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.entry(session_id.clone()).or_default().push(kind);
+    }
+
+    /// Subscribe to future events in a session. If `last_event_id` is given,
+    /// any retained events after that ID will be replayed first. An ID that
+    /// hasn't been reached yet, e.g. from before a server restart, replays
+    /// nothing. The stream ends after the session is closed.
+    pub fn subscribe(
+        &self,
+        session_id: &SessionId,
+        last_event_id: Option<u64>,
+    ) -> impl Stream<Item = Event> + Send + use<> {
+        // Subscribe and grab history under the same lock, so no events can
+        // fall in between the two
+        let subscription = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let session = sessions.entry(session_id.clone()).or_default();
+            // Otherwise, events would be skipped until the IDs caught up
+            let latest_id = session.next_id - 1;
+            let last_id =
+                last_event_id.map_or(latest_id, |id| id.min(latest_id));
+            Subscription {
+                hub: self.clone(),
+                session_id: session_id.clone(),
+                receiver: session.sender.subscribe(),
+                pending: session.history_after(last_id),
+                last_id,
+                done: false,
+            }
+        };
+        stream::unfold(subscription, |mut subscription| async move {
+            let event = subscription.next().await?;
+            Some((event, subscription))
+        })
+    }
+
+    /// Close a session, sending a final [EventKind::Expired] event to all of
+    /// its subscribers and ending their streams
+    pub fn close(&self, session_id: &SessionId) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(mut session) = sessions.remove(session_id) {
+            session.push(EventKind::Expired);
+        }
+    }
+
+    fn history_after(
+        &self,
+        session_id: &SessionId,
+        id: u64,
+    ) -> VecDeque<Event> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .get(session_id)
+            .map(|session| session.history_after(id))
+            .unwrap_or_default()
+    }
+}
+
+/// Event state for a single session
+#[derive(Debug)]
+struct SessionEvents {
+    sender: broadcast::Sender<Event>,
+    /// Recent events, oldest first
+    history: VecDeque<Event>,
+    /// ID to assign to the next event. IDs start at 1 so that 0 can be used
+    /// to mean "before all events"
+    next_id: u64,
+}
+
+impl SessionEvents {
+    fn push(&mut self, kind: EventKind) {
+        let event = Event {
+            id: self.next_id,
+            kind,
+        };
+        self.next_id += 1;
+        if self.history.len() >= HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(event.clone());
+        // Error just means there are no subscribers
+        let _ = self.sender.send(event);
+    }
+
+    fn history_after(&self, id: u64) -> VecDeque<Event> {
+        self.history
+            .iter()
+            .filter(|event| event.id > id)
+            .cloned()
+            .collect()
+    }
+}
+
+impl Default for SessionEvents {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(HISTORY_SIZE).0,
+            history: VecDeque::new(),
+            next_id: 1,
+        }
+    }
+}
+
+/// State for a single subscriber's stream
+struct Subscription {
+    hub: EventHub,
+    session_id: SessionId,
+    receiver: broadcast::Receiver<Event>,
+    /// Events to emit before pulling from the receiver
+    pending: VecDeque<Event>,
+    /// ID of the last emitted event
+    last_id: u64,
+    /// Has the session been closed?
+    done: bool,
+}
+
+impl Subscription {
+    async fn next(&mut self) -> Option<Event> {
+        loop {
+            if self.done {
+                return None;
+            }
+            if let Some(event) = self.pending.pop_front() {
+                return Some(self.emit(event));
+            }
+            match self.receiver.recv().await {
+                // Skip anything we already replayed from history
+                Ok(event) if event.id <= self.last_id => {}
+                Ok(event) => return Some(self.emit(event)),
+                // We fell behind, recover what we can from the history
+                Err(RecvError::Lagged(_)) => {
+                    self.pending =
+                        self.hub.history_after(&self.session_id, self.last_id);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    fn emit(&mut self, event: Event) -> Event {
+        self.last_id = event.id;
+        self.done = matches!(event.kind, EventKind::Expired);
+        event
+    }
+}
+
+/// A change within a session
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    /// Unique ID of this event within its session. IDs are sequential
+    pub id: u64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", content = "fish", rename_all = "snake_case")]
+pub enum EventKind {
+    Created(Fish),
+    Updated(Fish),
+    Deleted(Fish),
+    /// Session has expired. This is always the final event in a stream
+    Expired,
+}
+
+impl EventKind {
+    /// Name of this event type
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Created(_) => "created",
+            Self::Updated(_) => "updated",
+            Self::Deleted(_) => "deleted",
+            Self::Expired => "expired",
+        }
+    }
+
+    /// The fish that changed, if any
+    pub fn fish(&self) -> Option<&Fish> {
+        match self {
+            Self::Created(fish) | Self::Updated(fish) | Self::Deleted(fish) => {
+                Some(fish)
+            }
+            Self::Expired => None,
+        }
+    }
+}
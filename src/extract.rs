@@ -0,0 +1,21 @@
+//! Wrappers around axum's extractors that reject with our own [Error] type,
+//! so that failures get the same response format as every other error
+
+use crate::Error;
+use axum::extract::{FromRequest, FromRequestParts};
+
+/// Deserialize query parameters. See [axum::extract::Query]
+#[derive(Debug, FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(Error))]
+pub struct Query<T>(pub T);
+
+/// Deserialize a `application/x-www-form-urlencoded` request body. See
+/// [axum::Form]
+#[derive(Debug, FromRequest)]
+#[from_request(via(axum::Form), rejection(Error))]
+pub struct Form<T>(pub T);
+
+/// Deserialize a JSON request body. See [axum::Json]
+#[derive(Debug, FromRequest)]
+#[from_request(via(axum::Json), rejection(Error))]
+pub struct Json<T>(pub T);
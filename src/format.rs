@@ -0,0 +1,251 @@
+//! Serialization formats for fish. Responses are encoded in whichever format
+//! the `Accept` header prefers, and request bodies are decoded according to
+//! their `Content-Type`.
+
+use crate::{
+    Error,
+    negotiate::{content_type, negotiate},
+};
+use axum::{
+    Json,
+    extract::{FromRequest, FromRequestParts, Request},
+    http::{HeaderMap, HeaderValue, header, request::Parts},
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use serde::{Serialize, de::DeserializeOwned};
+use std::{
+    error::Error as StdError,
+    fmt::{self, Display},
+};
+
+/// A media type that fish can be encoded in
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Format {
+    #[default]
+    Json,
+    Yaml,
+    Csv,
+    Xml,
+    MessagePack,
+    Cbor,
+}
+
+impl Format {
+    /// Every format, in order of server preference
+    pub const ALL: &[Self] = &[
+        Self::Json,
+        Self::Yaml,
+        Self::Csv,
+        Self::Xml,
+        Self::MessagePack,
+        Self::Cbor,
+    ];
+
+    /// Canonical media type for the format
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Yaml => "application/yaml",
+            Self::Csv => "text/csv",
+            Self::Xml => "application/xml",
+            Self::MessagePack => "application/msgpack",
+            Self::Cbor => "application/cbor",
+        }
+    }
+
+    /// Canonical media types for every format
+    pub fn media_types() -> Vec<&'static str> {
+        Self::ALL.iter().map(|format| format.media_type()).collect()
+    }
+
+    /// Get the format of a media type. Common aliases are accepted too, as
+    /// well as any `+json` type for compatibility with plain JSON bodies.
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "application/json" => Some(Self::Json),
+            "application/yaml" | "application/x-yaml" | "text/yaml" => {
+                Some(Self::Yaml)
+            }
+            "text/csv" => Some(Self::Csv),
+            "application/xml" | "text/xml" => Some(Self::Xml),
+            "application/msgpack"
+            | "application/x-msgpack"
+            | "application/vnd.msgpack" => Some(Self::MessagePack),
+            "application/cbor" => Some(Self::Cbor),
+            _ if media_type.ends_with("+json") => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    /// Get the format of the request body from its `Content-Type`
+    pub fn from_content_type(headers: &HeaderMap) -> Option<Self> {
+        Self::from_media_type(&content_type(headers)?)
+    }
+
+    /// Encode a response body
+    pub fn encode(self, value: &impl Encode) -> Vec<u8> {
+        let encoded = match self {
+            Self::Json => serde_json::to_vec(value).map_err(Into::into),
+            Self::Yaml => serde_norway::to_string(value)
+                .map(String::into_bytes)
+                .map_err(Into::into),
+            Self::Csv => {
+                let mut writer = csv::Writer::from_writer(Vec::new());
+                value
+                    .write_csv(&mut writer)
+                    .map_err(Into::into)
+                    .and_then(|()| writer.into_inner().map_err(Into::into))
+            }
+            Self::Xml => {
+                value.to_xml().map(String::into_bytes).map_err(Into::into)
+            }
+            // Encode structs as maps rather than arrays, so they have field
+            // names like every other format
+            Self::MessagePack => {
+                rmp_serde::to_vec_named(value).map_err(Into::into)
+            }
+            Self::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::into_writer(value, &mut buffer)
+                    .map(|()| buffer)
+                    .map_err(Into::into)
+            }
+        };
+        encoded.unwrap_or_else(|error: Box<dyn StdError>| {
+            panic!("Error encoding response as {self}: {error}")
+        })
+    }
+
+    /// Decode a request body
+    pub fn decode<T: DeserializeOwned>(self, body: &[u8]) -> crate::Result<T> {
+        let decoded = match self {
+            // Use axum's decoder so errors point to the offending field
+            Self::Json => {
+                let Json(value) = Json::from_bytes(body)?;
+                return Ok(value);
+            }
+            Self::Yaml => serde_norway::from_slice(body).map_err(Into::into),
+            Self::Csv => decode_csv(body),
+            Self::Xml => match str::from_utf8(body) {
+                Ok(body) => quick_xml::de::from_str(body).map_err(Into::into),
+                Err(error) => Err(error.into()),
+            },
+            Self::MessagePack => {
+                rmp_serde::from_slice(body).map_err(Into::into)
+            }
+            Self::Cbor => ciborium::from_reader(body).map_err(Into::into),
+        };
+        decoded.map_err(|error: Box<dyn StdError>| Error::InvalidBody {
+            format: self,
+            message: error.to_string(),
+        })
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Json => "JSON",
+            Self::Yaml => "YAML",
+            Self::Csv => "CSV",
+            Self::Xml => "XML",
+            Self::MessagePack => "MessagePack",
+            Self::Cbor => "CBOR",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Extracting a format negotiates the response format from the `Accept`
+/// header. If none of the formats are acceptable, the request is rejected
+/// before the handler runs.
+impl<S: Send + Sync> FromRequestParts<S> for Format {
+    type Rejection = Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let available = Self::media_types();
+        let Some(media_type) = negotiate(&parts.headers, &available) else {
+            return Err(Error::NotAcceptable { available });
+        };
+        Ok(Self::from_media_type(media_type)
+            .expect("Available types are all valid formats"))
+    }
+}
+
+/// A response body that can be encoded in every format. Most formats can
+/// encode anything serializable, but XML needs a name for the root element
+/// and CSV can only encode flat records.
+pub trait Encode: Serialize {
+    /// Encode as an XML document
+    fn to_xml(&self) -> Result<String, quick_xml::SeError>;
+
+    /// Write as CSV records. The header row is written automatically from
+    /// the first record, so write it explicitly if there may be none
+    fn write_csv(
+        &self,
+        writer: &mut csv::Writer<Vec<u8>>,
+    ) -> Result<(), csv::Error>;
+}
+
+/// A response body in a negotiated format
+#[derive(Debug)]
+pub struct Encoded<T>(pub Format, pub T);
+
+impl<T: Encode> IntoResponse for Encoded<T> {
+    fn into_response(self) -> Response {
+        let Self(format, value) = self;
+        let content_type = match format {
+            // CSV defaults to ASCII (RFC 4180), so the charset is needed
+            Format::Csv => "text/csv; charset=utf-8",
+            _ => format.media_type(),
+        };
+        (
+            [
+                (header::CONTENT_TYPE, HeaderValue::from_static(content_type)),
+                (header::VARY, HeaderValue::from_static("accept")),
+            ],
+            format.encode(&value),
+        )
+            .into_response()
+    }
+}
+
+/// Decode a request body in any [Format], according to its `Content-Type`
+#[derive(Debug)]
+pub struct Decoded<T>(pub T);
+
+impl<S: Send + Sync, T: DeserializeOwned> FromRequest<S> for Decoded<T> {
+    type Rejection = Error;
+
+    async fn from_request(
+        request: Request,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let format =
+            Format::from_content_type(request.headers()).ok_or_else(|| {
+                Error::UnsupportedMediaType {
+                    accepted: Format::media_types(),
+                }
+            })?;
+        let body = Bytes::from_request(request, state).await?;
+        format.decode(&body).map(Self)
+    }
+}
+
+/// Decode a CSV body, which must have a header row and exactly one record
+fn decode_csv<T: DeserializeOwned>(
+    body: &[u8],
+) -> Result<T, Box<dyn StdError>> {
+    let mut records = csv::Reader::from_reader(body).into_deserialize();
+    let record = records
+        .next()
+        .ok_or("Expected a record after the header row")??;
+    if records.next().is_some() {
+        return Err("Expected exactly one record".into());
+    }
+    Ok(record)
+}
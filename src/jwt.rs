@@ -0,0 +1,387 @@
+//! Signed JWT session tokens (RFC 7519). A token carries its session ID, so
+//! it's verified without a DB lookup. Keys are generated on startup and
+//! rotated periodically. Retired keys are kept around until every token they
+//! signed has expired, and public keys are published at
+//! `/.well-known/jwks.json`.
+
+use crate::{Error, data::SessionId, scope::Scopes};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use ed25519_dalek::{Signer as _, Verifier as _};
+use hmac::{Hmac, Mac};
+use jiff::Timestamp;
+use rsa::{
+    RsaPrivateKey, pkcs1v15, rand_core::OsRng, signature::SignatureEncoding,
+    traits::PublicKeyParts,
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sha2::Sha256;
+use std::{
+    fmt::{self, Debug},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+/// `iss` claim for every token
+const ISSUER: &str = "shoal";
+/// Size of generated RSA keys
+const RSA_BITS: usize = 2048;
+
+/// Signature algorithm for a token
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Algorithm {
+    /// HMAC with SHA-256. The key is secret, so it isn't in the JWKS
+    HS256,
+    /// RSASSA-PKCS1-v1_5 with SHA-256
+    RS256,
+    /// Ed25519
+    EdDSA,
+}
+
+impl Algorithm {
+    const ALL: &[Self] = &[Self::HS256, Self::RS256, Self::EdDSA];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::HS256 => "HS256",
+            Self::RS256 => "RS256",
+            Self::EdDSA => "EdDSA",
+        }
+    }
+}
+
+/// Claims in a session token
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Claims {
+    pub iss: String,
+    /// Session ID
+    pub sub: SessionId,
+    pub iat: i64,
+    pub nbf: i64,
+    pub exp: i64,
+    /// Space-separated scopes granted to the token. Tokens from before
+    /// scopes existed had full access
+    #[serde(default = "Scopes::all")]
+    pub scope: Scopes,
+}
+
+impl Claims {
+    /// Claims for a token that's valid from now until the session expires
+    pub fn new(
+        session_id: SessionId,
+        expires_at: Timestamp,
+        scope: Scopes,
+    ) -> Self {
+        let now = Timestamp::now().as_second();
+        Self {
+            iss: ISSUER.to_owned(),
+            sub: session_id,
+            iat: now,
+            nbf: now,
+            exp: expires_at.as_second(),
+            scope,
+        }
+    }
+}
+
+/// Signing keys, shared between requests. This uses an Arc so it is cheap to
+/// clone.
+#[derive(Clone, Debug)]
+pub struct Keys {
+    /// Newest first. Only the first generation is used for signing
+    generations: Arc<RwLock<Vec<KeyGeneration>>>,
+    /// HS256 key to use instead of generating one, so other tools can verify
+    /// HS256 tokens
+    secret: Option<Arc<[u8]>>,
+    /// Allowed clock skew when checking `exp` and `nbf`
+    leeway: Duration,
+    /// How long to keep retired keys, so their tokens can still be verified.
+    /// This should be the longest possible token lifetime.
+    retention: Duration,
+}
+
+impl Keys {
+    /// Generate the initial set of keys
+    pub fn new(
+        secret: Option<Vec<u8>>,
+        leeway: Duration,
+        retention: Duration,
+    ) -> Self {
+        let secret: Option<Arc<[u8]>> = secret.map(Into::into);
+        let generation = KeyGeneration::generate(secret.as_deref());
+        Self {
+            generations: Arc::new(RwLock::new(vec![generation])),
+            secret,
+            leeway,
+            retention,
+        }
+    }
+
+    /// Replace the signing keys with newly generated ones. The old keys are
+    /// kept for verification until their tokens have expired. RSA key
+    /// generation is slow, so this blocks for a while.
+    pub fn rotate(&self) {
+        let generation = KeyGeneration::generate(self.secret.as_deref());
+        let now = Timestamp::now();
+        let mut generations = self.generations.write().unwrap();
+        if let Some(current) = generations.first_mut() {
+            current.retired_at = Some(now);
+        }
+        generations.retain(|generation| {
+            generation
+                .retired_at
+                .is_none_or(|retired_at| retired_at + self.retention > now)
+        });
+        generations.insert(0, generation);
+    }
+
+    /// Sign a token with the current key for the algorithm
+    pub fn sign(&self, algorithm: Algorithm, claims: &Claims) -> String {
+        let generations = self.generations.read().unwrap();
+        let generation = &generations[0];
+        let header = Header {
+            alg: algorithm.name().to_owned(),
+            kid: Some(generation.kid(algorithm)),
+            typ: Some("JWT".to_owned()),
+        };
+        let signing_input =
+            format!("{}.{}", encode_json(&header), encode_json(claims));
+        let signature = generation.sign(algorithm, signing_input.as_bytes());
+        format!(
+            "{signing_input}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    /// Verify a token's signature and validity period, and return its claims
+    pub fn verify(&self, token: &str) -> crate::Result<Claims> {
+        let (signing_input, signature) = token
+            .rsplit_once('.')
+            .ok_or(Error::InvalidToken("Malformed token"))?;
+        let (header, payload) = signing_input
+            .split_once('.')
+            .ok_or(Error::InvalidToken("Malformed token"))?;
+        let header: Header = decode_json(header)?;
+        let kid = header
+            .kid
+            .ok_or(Error::InvalidToken("Token has no `kid` header"))?;
+
+        let generations = self.generations.read().unwrap();
+        let (generation, algorithm) = generations
+            .iter()
+            .find_map(|generation| {
+                Algorithm::ALL
+                    .iter()
+                    .find(|algorithm| generation.kid(**algorithm) == kid)
+                    .map(|algorithm| (generation, *algorithm))
+            })
+            .ok_or(Error::InvalidToken("Unknown signing key"))?;
+        // Never let the token pick the algorithm for a key
+        if header.alg != algorithm.name() {
+            return Err(Error::InvalidToken(
+                "Token algorithm doesn't match its key",
+            ));
+        }
+        let signature = BASE64_URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| Error::InvalidToken("Malformed token"))?;
+        if !generation.verify(algorithm, signing_input.as_bytes(), &signature) {
+            return Err(Error::InvalidToken("Invalid signature"));
+        }
+
+        let claims: Claims = decode_json(payload)?;
+        let now = Timestamp::now().as_second();
+        let leeway = self.leeway.as_secs() as i64;
+        if claims.iss != ISSUER {
+            return Err(Error::InvalidToken("Unknown issuer"));
+        }
+        if claims.exp + leeway <= now {
+            return Err(Error::InvalidToken("Token has expired"));
+        }
+        if claims.nbf - leeway > now {
+            return Err(Error::InvalidToken("Token is not valid yet"));
+        }
+        Ok(claims)
+    }
+
+    /// Public keys for every retained generation, newest first
+    pub fn jwks(&self) -> Jwks {
+        let generations = self.generations.read().unwrap();
+        let keys = generations
+            .iter()
+            .flat_map(|generation| {
+                let public = generation.rsa.to_public_key();
+                [
+                    Jwk {
+                        kty: "RSA",
+                        kid: generation.kid(Algorithm::RS256),
+                        alg: Algorithm::RS256.name(),
+                        key_use: "sig",
+                        n: Some(encode(public.n().to_bytes_be())),
+                        e: Some(encode(public.e().to_bytes_be())),
+                        crv: None,
+                        x: None,
+                    },
+                    Jwk {
+                        kty: "OKP",
+                        kid: generation.kid(Algorithm::EdDSA),
+                        alg: Algorithm::EdDSA.name(),
+                        key_use: "sig",
+                        n: None,
+                        e: None,
+                        crv: Some("Ed25519"),
+                        x: Some(encode(
+                            generation.ed25519.verifying_key().as_bytes(),
+                        )),
+                    },
+                ]
+            })
+            .collect();
+        Jwks { keys }
+    }
+}
+
+/// One set of keys, one per algorithm
+struct KeyGeneration {
+    /// Random ID, used to build the `kid` for each key
+    id: String,
+    /// When this generation stopped being used for signing
+    retired_at: Option<Timestamp>,
+    hmac: Vec<u8>,
+    rsa: RsaPrivateKey,
+    ed25519: ed25519_dalek::SigningKey,
+}
+
+impl KeyGeneration {
+    fn generate(secret: Option<&[u8]>) -> Self {
+        Self {
+            id: format!("{:08x}", rand::random::<u32>()),
+            retired_at: None,
+            hmac: secret.map_or_else(
+                || rand::random::<[u8; 32]>().to_vec(),
+                <[u8]>::to_vec,
+            ),
+            rsa: RsaPrivateKey::new(&mut OsRng, RSA_BITS)
+                .expect("RSA key generation failed"),
+            ed25519: ed25519_dalek::SigningKey::from_bytes(&rand::random()),
+        }
+    }
+
+    fn kid(&self, algorithm: Algorithm) -> String {
+        format!("{}-{}", self.id, algorithm.name().to_ascii_lowercase())
+    }
+
+    fn sign(&self, algorithm: Algorithm, message: &[u8]) -> Vec<u8> {
+        match algorithm {
+            Algorithm::HS256 => {
+                let mut mac = self.hmac();
+                mac.update(message);
+                mac.finalize().into_bytes().to_vec()
+            }
+            Algorithm::RS256 => {
+                pkcs1v15::SigningKey::<Sha256>::new(self.rsa.clone())
+                    .sign(message)
+                    .to_vec()
+            }
+            Algorithm::EdDSA => self.ed25519.sign(message).to_vec(),
+        }
+    }
+
+    fn verify(
+        &self,
+        algorithm: Algorithm,
+        message: &[u8],
+        signature: &[u8],
+    ) -> bool {
+        match algorithm {
+            Algorithm::HS256 => {
+                let mut mac = self.hmac();
+                mac.update(message);
+                mac.verify_slice(signature).is_ok()
+            }
+            Algorithm::RS256 => {
+                let key = pkcs1v15::VerifyingKey::<Sha256>::new(
+                    self.rsa.to_public_key(),
+                );
+                pkcs1v15::Signature::try_from(signature).is_ok_and(
+                    |signature| key.verify(message, &signature).is_ok(),
+                )
+            }
+            Algorithm::EdDSA => ed25519_dalek::Signature::from_slice(signature)
+                .is_ok_and(|signature| {
+                    self.ed25519
+                        .verifying_key()
+                        .verify(message, &signature)
+                        .is_ok()
+                }),
+        }
+    }
+
+    fn hmac(&self) -> Hmac<Sha256> {
+        Hmac::new_from_slice(&self.hmac)
+            .expect("HMAC accepts keys of any length")
+    }
+}
+
+/// Keys are secret, so only show the ID
+impl Debug for KeyGeneration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyGeneration")
+            .field("id", &self.id)
+            .field("retired_at", &self.retired_at)
+            .finish_non_exhaustive()
+    }
+}
+
+/// JOSE header
+#[derive(Debug, Deserialize, Serialize)]
+struct Header {
+    alg: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    typ: Option<String>,
+}
+
+/// Response body for `GET /.well-known/jwks.json` (RFC 7517)
+#[derive(Debug, Serialize)]
+pub struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+/// A public key
+#[derive(Debug, Serialize)]
+struct Jwk {
+    kty: &'static str,
+    kid: String,
+    alg: &'static str,
+    #[serde(rename = "use")]
+    key_use: &'static str,
+    /// RSA modulus
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<String>,
+    /// RSA exponent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    e: Option<String>,
+    /// Curve for OKP keys
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crv: Option<&'static str>,
+    /// OKP public key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    x: Option<String>,
+}
+
+fn encode(bytes: impl AsRef<[u8]>) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn encode_json(value: &impl Serialize) -> String {
+    encode(serde_json::to_vec(value).expect("Serialization is infallible"))
+}
+
+fn decode_json<T: DeserializeOwned>(segment: &str) -> crate::Result<T> {
+    BASE64_URL_SAFE_NO_PAD
+        .decode(segment)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or(Error::InvalidToken("Malformed token"))
+}
@@ -1,23 +1,43 @@
 #![forbid(unsafe_code)]
 #![deny(clippy::all)]
 
+mod auth;
+mod compression;
+mod conditional;
 mod data;
 mod error;
+mod events;
+mod extract;
+mod format;
+mod jwt;
+mod negotiate;
+mod pool;
+mod problem;
+mod rate_limit;
 mod routes;
+mod scope;
+mod validate;
 
 use crate::{
-    data::Store,
+    data::{SessionTtl, Store},
     error::{Error, Result},
+    rate_limit::{Quota, RateLimiter},
 };
 use axum::{
     Extension, Router,
     body::Body,
+    extract::DefaultBodyLimit,
+    handler::Handler,
     http::header::CONTENT_TYPE,
+    middleware,
     response::{Html, Redirect, Response},
     routing::{any, get, post},
 };
 use routes::*;
-use std::{env, time::Duration};
+use std::{
+    env, fmt::Display, net::SocketAddr, path::PathBuf, str::FromStr,
+    time::Duration,
+};
 use tokio::signal::unix::{SignalKind, signal};
 use tower_http::trace::TraceLayer;
 use tracing::{error, info, warn};
@@ -26,24 +46,69 @@ use tracing::{error, info, warn};
 const DOCS_HTML: &[u8] = include_bytes!("../static/docs.html");
 const OPENAPI_YML: &[u8] = include_bytes!("../static/openapi.yml");
 
+/// Cap on `MAX_SESSION_TTL`, so expiration times can always be calculated
+/// without overflowing a timestamp
+const SESSION_TTL_LIMIT: u64 = 100 * 365 * 24 * 60 * 60;
+
 #[tokio::main]
 async fn main() -> crate::Result<()> {
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::DEBUG)
         .init();
 
-    // Initial an in-memory DB for fish
-    let store = Store::new()?;
+    // Initialize the DB for fish. Without a path, it's in-memory
+    let database_path = env::var_os("DATABASE_PATH").map(PathBuf::from);
+    let generated_fish = env_parse("SEED_FISH").unwrap_or(0);
+    let mut max_ttl = env_seconds("MAX_SESSION_TTL").unwrap_or(24 * 60 * 60);
+    if max_ttl > SESSION_TTL_LIMIT {
+        warn!("Limiting MAX_SESSION_TTL to {SESSION_TTL_LIMIT}");
+        max_ttl = SESSION_TTL_LIMIT;
+    }
+    let session_ttl = SessionTtl {
+        default: Duration::from_secs(
+            env_seconds("SESSION_TTL").unwrap_or(60 * 60).min(max_ttl),
+        ),
+        max: Duration::from_secs(max_ttl),
+    };
+    let store =
+        Store::new(database_path.as_deref(), generated_fish, session_ttl)?;
+    // Retired keys are kept until every token they signed has expired
+    let keys = jwt::Keys::new(
+        env::var("JWT_SECRET").ok().map(String::into_bytes),
+        Duration::from_secs(env_seconds("JWT_LEEWAY").unwrap_or(30)),
+        session_ttl.max,
+    );
+    let key_rotation = Duration::from_secs(
+        env_seconds("JWT_KEY_ROTATION").unwrap_or(24 * 60 * 60),
+    );
+    // Rate limits are off unless configured
+    let rate_limit_window = Duration::from_secs(
+        env_seconds("RATE_LIMIT_WINDOW").unwrap_or(60).max(1),
+    );
+    let quota = |name| {
+        let limit = env_parse(name).filter(|limit| *limit > 0)?;
+        Some(Quota {
+            limit,
+            window: rate_limit_window,
+        })
+    };
+    let rate_limiter =
+        RateLimiter::new(quota("RATE_LIMIT_SESSION"), quota("RATE_LIMIT_IP"));
 
     // Start background tasks
     tokio::spawn(reap_sessions(store.clone()));
     tokio::spawn(listen_for_dump(store.clone()));
+    tokio::spawn(rotate_keys(keys.clone(), key_rotation));
+
+    // Compressed request bodies are only accepted on some routes
+    let decompress = middleware::from_fn(compression::decompress);
 
     // Build our application with routes
     let app = Router::new()
         // API docs
         .route("/", get(|| async { Redirect::permanent("/docs") }))
         .route("/docs", get(|| async { Html(DOCS_HTML) }))
+        .route("/graphiql", get(graphiql))
         .route(
             "/openapi.yml",
             get(|| async {
@@ -55,26 +120,116 @@ async fn main() -> crate::Result<()> {
         )
         // Routes
         .route("/login", post(login))
-        .route("/fish", get(list_fish).post(create_fish))
+        .route(
+            "/.well-known/oauth-authorization-server",
+            get(oauth_metadata),
+        )
+        .route("/.well-known/jwks.json", get(jwks))
+        .route("/oauth/authorize", get(authorize_page).post(authorize))
+        .route("/oauth/token", post(token))
+        .route("/logout", post(logout))
+        .route("/session", get(get_session))
+        .route("/session/refresh", post(refresh_session))
+        .route("/session/clone", post(clone_session))
+        .route("/session/snapshot", get(get_snapshot))
+        .route(
+            "/session/restore",
+            post(restore_snapshot)
+                .layer(DefaultBodyLimit::max(MAX_SNAPSHOT_SIZE)),
+        )
+        .route(
+            "/fish",
+            get(list_fish).post(create_fish.layer(decompress.clone())),
+        )
+        .route("/fish/bulk", post(bulk_fish.layer(decompress.clone())))
+        .route("/fish/export", get(export_fish))
+        .route("/fish/import", post(import_fish))
+        .route("/fish/events", get(fish_events))
         .route(
             "/fish/{id}",
-            get(get_fish_by_id).patch(update_fish).delete(delete_fish),
+            get(get_fish_by_id)
+                .put(replace_fish)
+                .patch(update_fish)
+                .delete(delete_fish),
+        )
+        .route(
+            "/fish/{id}/image",
+            get(get_image).post(
+                upload_image.layer(DefaultBodyLimit::max(MAX_IMAGE_SIZE)),
+            ),
         )
-        .route("/anything", any(anything))
-        .route("/anything/{*path}", any(anything))
+        .route("/anything", any(anything).layer(decompress.clone()))
+        .route("/anything/{*path}", any(anything).layer(decompress))
         .route("/delay/{duration}", get(delay))
+        .route("/status/{codes}", any(status))
+        .route(
+            "/response-headers",
+            get(response_headers).post(response_headers),
+        )
+        .route("/bytes/{n}", get(random_bytes))
+        .route("/stream-bytes/{n}", get(stream_bytes))
+        .route("/base64/{value}", get(decode_base64))
+        .route("/range/{n}", get(range))
+        .route("/redirect/{n}", any(redirect))
+        .route("/relative-redirect/{n}", any(relative_redirect))
+        .route("/absolute-redirect/{n}", any(absolute_redirect))
+        .route("/redirect-to", any(redirect_to))
+        .route("/redirect-loop", any(redirect_loop))
+        .route("/gzip", get(gzip))
+        .route("/deflate", get(deflate))
+        .route("/brotli", get(brotli))
+        .route("/cookies", get(cookies))
+        .route("/cookies/set", get(set_cookies))
+        .route("/cookies/delete", get(delete_cookies))
+        .route("/graphql", post(graphql))
+        .route("/graphql/ws", get(graphql_ws))
+        .route("/ws", get(websocket))
+        .route("/ws/echo", get(websocket_echo))
         .fallback(|| async { Error::NotFound })
+        // Needs the keys to identify sessions, so it goes inside them
+        .layer(middleware::from_fn_with_state(
+            rate_limiter,
+            rate_limit::rate_limit,
+        ))
         .layer(Extension(store))
+        .layer(Extension(graphql_schema()))
+        .layer(Extension(keys))
+        .layer(middleware::from_fn(problem::problem_details))
+        // Outside problem details, so rewritten bodies are compressed too
+        .layer(compression::compression())
         .layer(TraceLayer::new_for_http());
 
     // Run the server
     let host = env::var("HOST").unwrap_or_else(|_| "127.0.0.1:3000".into());
     let listener = tokio::net::TcpListener::bind(&host).await?;
     println!("Listening on http://{host}");
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        // Rate limiting needs the client's address
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
     Ok(())
 }
 
+/// Read a number of seconds from an environment variable
+fn env_seconds(name: &str) -> Option<u64> {
+    env_parse(name)
+}
+
+/// Parse an environment variable. If it's set but invalid, warn and return
+/// `None` so the default is used, rather than silently ignoring a typo.
+fn env_parse<T: FromStr<Err: Display>>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(error) => {
+            warn!("Ignoring invalid {name} `{value}`: {error}");
+            None
+        }
+    }
+}
+
 /// Background task to reap expired sessions
 async fn reap_sessions(store: Store) {
     loop {
@@ -92,6 +247,19 @@ async fn reap_sessions(store: Store) {
     }
 }
 
+/// Background task to periodically replace the JWT signing keys
+async fn rotate_keys(keys: jwt::Keys, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let keys = keys.clone();
+        // Key generation is CPU-heavy, so keep it off the async runtime
+        tokio::task::spawn_blocking(move || keys.rotate())
+            .await
+            .expect("Key rotation panicked");
+        info!("Rotated JWT signing keys");
+    }
+}
+
 /// Background task to listen for SIGUSR1, which triggers a database dump
 async fn listen_for_dump(store: Store) -> Result<()> {
     let mut stream = signal(SignalKind::user_defined1())?;
@@ -0,0 +1,85 @@
+//! Content negotiation helpers
+
+use axum::http::{HeaderMap, header};
+
+/// Pick the best media type to respond with, based on the request's `Accept`
+/// header. `available` should be ordered by server preference; it's used to
+/// break ties between equally weighted types. If the header is missing, the
+/// first available type is used. Return `None` if none of the available types
+/// are acceptable.
+pub fn negotiate<'a>(
+    headers: &HeaderMap,
+    available: &[&'a str],
+) -> Option<&'a str> {
+    let Some(accept) = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return available.first().copied();
+    };
+    let ranges: Vec<MediaRange> =
+        accept.split(',').filter_map(MediaRange::parse).collect();
+
+    let mut best: Option<(&str, f32)> = None;
+    for &media_type in available {
+        // Use the weight of the most specific range that matches this type
+        let Some(range) = ranges
+            .iter()
+            .filter(|range| range.matches(media_type))
+            .max_by_key(|range| range.specificity())
+        else {
+            continue;
+        };
+        if range.q > 0.0 && best.is_none_or(|(_, q)| range.q > q) {
+            best = Some((media_type, range.q));
+        }
+    }
+    best.map(|(media_type, _)| media_type)
+}
+
+/// Does the request's `Content-Type` header match the given media type?
+/// Parameters such as `charset` are ignored.
+pub fn has_content_type(headers: &HeaderMap, media_type: &str) -> bool {
+    content_type(headers).is_some_and(|value| value == media_type)
+}
+
+/// Get the essence (`type/subtype`) of the request's `Content-Type` header,
+/// lowercased and with parameters stripped
+pub fn content_type(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    let essence = value.split(';').next()?.trim();
+    Some(essence.to_ascii_lowercase())
+}
+
+/// One entry in an `Accept` header, e.g. `text/*;q=0.5`
+struct MediaRange<'a> {
+    type_: &'a str,
+    subtype: &'a str,
+    q: f32,
+}
+
+impl<'a> MediaRange<'a> {
+    fn parse(s: &'a str) -> Option<Self> {
+        let mut parts = s.split(';');
+        let (type_, subtype) = parts.next()?.trim().split_once('/')?;
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse().ok())
+            .unwrap_or(1.0);
+        Some(Self { type_, subtype, q })
+    }
+
+    fn matches(&self, media_type: &str) -> bool {
+        let Some((type_, subtype)) = media_type.split_once('/') else {
+            return false;
+        };
+        (self.type_ == "*" || self.type_.eq_ignore_ascii_case(type_))
+            && (self.subtype == "*"
+                || self.subtype.eq_ignore_ascii_case(subtype))
+    }
+
+    /// `*/*` < `type/*` < `type/subtype`
+    fn specificity(&self) -> u8 {
+        u8::from(self.type_ != "*") + u8::from(self.subtype != "*")
+    }
+}
@@ -0,0 +1,83 @@
+//! A minimal pool of SQLite connections. rusqlite is synchronous, so all
+//! queries run on tokio's blocking thread pool to keep them off the async
+//! runtime.
+
+use rusqlite::Connection;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// A fixed set of connections. Callers wait for a free connection, in the
+/// order they arrived.
+#[derive(Debug)]
+pub struct Pool {
+    /// Connections not currently in use
+    idle: Mutex<Vec<Connection>>,
+    /// One permit per connection. Holding a permit guarantees there's an idle
+    /// connection to take.
+    permits: Arc<Semaphore>,
+}
+
+impl Pool {
+    pub fn new(connections: Vec<Connection>) -> Arc<Self> {
+        Arc::new(Self {
+            permits: Arc::new(Semaphore::new(connections.len())),
+            idle: Mutex::new(connections),
+        })
+    }
+
+    /// Run a function with a connection from the pool, on a blocking thread
+    pub async fn run<T, F>(self: &Arc<Self>, f: F) -> crate::Result<T>
+    where
+        T: 'static + Send,
+        F: 'static + FnOnce(&mut Connection) -> crate::Result<T> + Send,
+    {
+        let mut connection = self.get().await;
+        tokio::task::spawn_blocking(move || f(connection.get_mut()))
+            .await
+            .expect("Database task panicked")
+    }
+
+    async fn get(self: &Arc<Self>) -> PooledConnection {
+        let permit = Arc::clone(&self.permits)
+            .acquire_owned()
+            .await
+            .expect("Semaphore is never closed");
+        let connection = self
+            .idle
+            .lock()
+            .unwrap()
+            .pop()
+            .expect("Permit guarantees an idle connection");
+        PooledConnection {
+            connection: Some(connection),
+            pool: Arc::clone(self),
+            _permit: permit,
+        }
+    }
+}
+
+/// A connection checked out of a [Pool]. It's returned when dropped.
+struct PooledConnection {
+    /// Always populated until dropped
+    connection: Option<Connection>,
+    pool: Arc<Pool>,
+    /// Released after the connection is returned, since fields are dropped
+    /// after [Drop::drop]
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledConnection {
+    fn get_mut(&mut self) -> &mut Connection {
+        self.connection
+            .as_mut()
+            .expect("Connection is present until drop")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.pool.idle.lock().unwrap().push(connection);
+        }
+    }
+}
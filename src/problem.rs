@@ -0,0 +1,99 @@
+//! Problem Details for HTTP APIs (RFC 9457). Error responses use the legacy
+//! [ErrorDetail](crate::error::ErrorDetail) format by default, and switch to
+//! `application/problem+json` if the client prefers it.
+
+use crate::negotiate::negotiate;
+use axum::{
+    extract::Request,
+    http::{HeaderValue, header},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+
+/// Media type for problem details
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Prefix for problem type URIs. These are `tag:` URIs (RFC 4151) because
+/// they identify problem types but aren't meant to be dereferenced.
+const TYPE_PREFIX: &str = "tag:shoal.lucaspickering.me,2025:problem:";
+
+/// A problem details object. Every error response carries one of these as a
+/// response extension, so [problem_details] can swap it in for the body.
+#[derive(Clone, Debug, Serialize)]
+pub struct Problem {
+    /// URI identifying the problem type
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Short summary of the problem type. This is the same for every
+    /// occurrence of the type.
+    pub title: &'static str,
+    pub status: u16,
+    /// Explanation specific to this occurrence of the problem
+    pub detail: String,
+    /// URI of the request that caused the problem
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    /// Additional members specific to the problem type
+    #[serde(flatten)]
+    pub extensions: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Problem {
+    pub fn new(
+        kind: &str,
+        title: &'static str,
+        status: u16,
+        detail: String,
+    ) -> Self {
+        Self {
+            kind: format!("{TYPE_PREFIX}{kind}"),
+            title,
+            status,
+            detail,
+            instance: None,
+            extensions: Default::default(),
+        }
+    }
+
+    /// Add an extension member
+    pub fn extension(mut self, name: &str, value: impl Serialize) -> Self {
+        self.extensions.insert(
+            name.to_owned(),
+            serde_json::to_value(value).expect("Serialization is infallible"),
+        );
+        self
+    }
+}
+
+/// Middleware to render error responses as `application/problem+json` if the
+/// client's `Accept` header prefers it over `application/json`. Legacy clients
+/// that accept anything get the original format.
+pub async fn problem_details(request: Request, next: Next) -> Response {
+    let wants_problem =
+        negotiate(request.headers(), &["application/json", PROBLEM_JSON])
+            == Some(PROBLEM_JSON);
+    let instance = request.uri().path().to_owned();
+    let mut response = next.run(request).await;
+
+    let Some(mut problem) = response.extensions_mut().remove::<Problem>()
+    else {
+        return response;
+    };
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept"));
+    if !wants_problem {
+        return response;
+    }
+
+    problem.instance = Some(instance);
+    let body =
+        serde_json::to_vec(&problem).expect("Serialization is infallible");
+    let headers = response.headers_mut();
+    headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+    headers.remove(header::CONTENT_LENGTH);
+    *response.body_mut() = body.into();
+    response
+}
@@ -0,0 +1,262 @@
+//! Token bucket rate limiting. Requests with a session are limited per
+//! session, and requests without one are limited per client IP. Each bucket
+//! holds up to `limit` requests and refills continuously over the window, so
+//! clients can burst up to the limit and then continue at the average rate.
+//!
+//! Limited responses describe the client's bucket in both the IETF
+//! `RateLimit-*` headers and the older `X-RateLimit-*` headers. When the
+//! bucket is empty, the response is a 429 with `Retry-After`.
+
+use crate::{
+    Error,
+    auth::{self, Credentials},
+    data::Store,
+    jwt::Keys,
+};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use jiff::Timestamp;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Maximum request rate for a single client
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    /// Requests allowed per window, which is also the burst size
+    pub limit: u32,
+    pub window: Duration,
+}
+
+impl Quota {
+    /// Tokens added to a bucket per second
+    fn rate(&self) -> f64 {
+        f64::from(self.limit) / self.window.as_secs_f64()
+    }
+}
+
+/// Shared state for rate limiting. This uses an Arc so it is safe and cheap to
+/// clone.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    /// Quota for each session. If `None`, sessions aren't limited
+    session: Option<Quota>,
+    /// Quota for each IP, for requests without a session. If `None`,
+    /// anonymous requests aren't limited
+    ip: Option<Quota>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    pub fn new(session: Option<Quota>, ip: Option<Quota>) -> Self {
+        Self {
+            session,
+            ip,
+            buckets: Arc::new(Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                pruned_at: Instant::now(),
+            })),
+        }
+    }
+
+    /// Take a token from a client's bucket. Return `None` if the client
+    /// isn't limited.
+    fn check(&self, client: Client) -> Option<Decision> {
+        let quota = match client {
+            Client::Session(_) => self.session?,
+            Client::Ip(_) => self.ip?,
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("Rate limiter poisoned");
+        buckets.prune(self, now);
+        let bucket = buckets.buckets.entry(client).or_insert(Bucket {
+            tokens: f64::from(quota.limit),
+            updated_at: now,
+        });
+        Some(bucket.take(quota, now))
+    }
+
+    /// Longest window of any quota. A bucket that hasn't been touched for
+    /// this long is definitely full
+    fn max_window(&self) -> Duration {
+        [self.session, self.ip]
+            .into_iter()
+            .flatten()
+            .map(|quota| quota.window)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// Middleware to enforce rate limits
+pub async fn rate_limit(
+    State(limiter): State<RateLimiter>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if limiter.session.is_none() && limiter.ip.is_none() {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let keys = parts
+        .extensions
+        .get::<Keys>()
+        .expect("Keys extension is always present");
+    let store = parts
+        .extensions
+        .get::<Store>()
+        .expect("Store extension is always present");
+    // Only verified sessions get their own bucket. Otherwise a client could
+    // dodge the IP limit by making up a new session ID for every request.
+    // Bad credentials fall back to the IP limit, and the route will reject
+    // them anyway
+    let client = match auth::credentials(&parts, keys) {
+        Ok(Some(Credentials::SessionId(session_id)))
+            if store.has_session(&session_id).await.unwrap_or(false) =>
+        {
+            Client::Session(session_id)
+        }
+        Ok(Some(Credentials::Token(claims))) => {
+            Client::Session(claims.sub.to_string())
+        }
+        Ok(Some(Credentials::SessionId(_)) | None) | Err(_) => {
+            Client::Ip(address.ip())
+        }
+    };
+    let request = Request::from_parts(parts, body);
+
+    let Some(decision) = limiter.check(client) else {
+        return next.run(request).await;
+    };
+    let mut response = match decision.retry_after {
+        Some(retry_after) => Error::RateLimited { retry_after }.into_response(),
+        None => next.run(request).await,
+    };
+    decision.write_headers(response.headers_mut());
+    response
+}
+
+/// Who a request is limited as
+#[derive(Debug, Eq, Hash, PartialEq)]
+enum Client {
+    Session(String),
+    Ip(IpAddr),
+}
+
+#[derive(Debug)]
+struct Buckets {
+    buckets: HashMap<Client, Bucket>,
+    /// Last time full buckets were dropped
+    pruned_at: Instant,
+}
+
+impl Buckets {
+    /// Drop buckets that have refilled completely, since they're the same as
+    /// a new bucket. This runs at most once per window, so it doesn't cost
+    /// much per request.
+    fn prune(&mut self, limiter: &RateLimiter, now: Instant) {
+        let max_window = limiter.max_window();
+        if now.duration_since(self.pruned_at) < max_window {
+            return;
+        }
+        self.buckets.retain(|client, bucket| {
+            let quota = match client {
+                Client::Session(_) => limiter.session,
+                Client::Ip(_) => limiter.ip,
+            };
+            quota.is_some_and(|quota| {
+                now.duration_since(bucket.updated_at) < quota.window
+            })
+        });
+        self.pruned_at = now;
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Fractional, because the bucket refills continuously
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    /// Refill the bucket for the time since it was last used, then take a
+    /// token if there is one
+    fn take(&mut self, quota: Quota, now: Instant) -> Decision {
+        let capacity = f64::from(quota.limit);
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * quota.rate()).min(capacity);
+        self.updated_at = now;
+
+        let retry_after = if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / quota.rate()))
+        };
+        Decision {
+            quota,
+            remaining: self.tokens.floor() as u32,
+            reset: Duration::from_secs_f64(
+                (capacity - self.tokens) / quota.rate(),
+            ),
+            retry_after,
+        }
+    }
+}
+
+/// Outcome of a rate limit check
+#[derive(Debug)]
+struct Decision {
+    quota: Quota,
+    /// Requests left in the bucket
+    remaining: u32,
+    /// Time until the bucket is full again
+    reset: Duration,
+    /// If the request was rejected, time until it can be retried
+    retry_after: Option<Duration>,
+}
+
+impl Decision {
+    fn write_headers(&self, headers: &mut HeaderMap) {
+        let reset = ceil_seconds(self.reset);
+        let reset_at = Timestamp::now().as_second() as u64 + reset;
+        let values = [
+            ("RateLimit-Limit", self.quota.limit.to_string()),
+            ("RateLimit-Remaining", self.remaining.to_string()),
+            ("RateLimit-Reset", reset.to_string()),
+            (
+                "RateLimit-Policy",
+                format!(
+                    "{};w={}",
+                    self.quota.limit,
+                    self.quota.window.as_secs()
+                ),
+            ),
+            ("X-RateLimit-Limit", self.quota.limit.to_string()),
+            ("X-RateLimit-Remaining", self.remaining.to_string()),
+            // By convention, this one is a Unix timestamp rather than a delay
+            ("X-RateLimit-Reset", reset_at.to_string()),
+        ];
+        for (name, value) in values {
+            headers.insert(
+                name,
+                HeaderValue::from_str(&value).expect("Values are ASCII"),
+            );
+        }
+    }
+}
+
+/// Round a duration up to whole seconds, so clients never retry early
+pub fn ceil_seconds(duration: Duration) -> u64 {
+    duration.as_secs_f64().ceil() as u64
+}
@@ -1,5 +1,24 @@
 mod fish;
+mod graphql;
 mod misc;
+mod oauth;
+mod session;
+mod ws;
 
 pub use fish::*;
+pub use graphql::*;
 pub use misc::*;
+pub use oauth::*;
+pub use session::*;
+pub use ws::*;
+
+use axum::http::{HeaderMap, header};
+
+/// Get the public URL of this server, to build absolute endpoint URLs.
+/// `X-Forwarded-Proto` is respected, for running behind a TLS proxy.
+fn base_url(headers: &HeaderMap) -> String {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let scheme = header("X-Forwarded-Proto").unwrap_or("http");
+    let host = header(header::HOST.as_str()).unwrap_or("localhost");
+    format!("{scheme}://{host}")
+}
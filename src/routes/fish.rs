@@ -1,57 +1,693 @@
 //! Fish-related routes
 
-use crate::data::{Fish, FishId, SessionId, SessionStore, Store};
-use axum::{Extension, Json, extract::Path};
+use crate::{
+    Error,
+    conditional::{Conditions, not_modified, validators},
+    data::{Cursor, Fish, FishId, Image, SessionStore, Sort},
+    events::EventKind,
+    extract::{self, Query},
+    format::{Decoded, Encode, Encoded, Format},
+    negotiate::{content_type, has_content_type, negotiate},
+    scope::Scope,
+    validate::{self, FieldError, Validate, Validator},
+};
+use axum::{
+    Json,
+    body::Body,
+    extract::{FromRequest, Multipart, OriginalUri, Path, Request},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{
+        IntoResponse, Response,
+        sse::{self, KeepAlive, Sse},
+    },
+};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use image::ImageFormat;
 use serde::{Deserialize, Serialize};
+use std::{io, iter, mem};
 
-/// Create new temporary session
-pub async fn login(
-    Extension(store): Extension<Store>,
-) -> crate::Result<Json<LoginResponse>> {
-    let response = store.create_session().await?;
-    Ok(Json(response))
-}
+/// Content types accepted by `PATCH /fish/{id}`. Besides the JSON patch
+/// formats, an object of fields to update can be in any [Format].
+pub const PATCH_TYPES: &[&str] = &[
+    "application/json",
+    "application/merge-patch+json",
+    "application/json-patch+json",
+    "application/yaml",
+    "text/csv",
+    "application/xml",
+    "application/msgpack",
+    "application/cbor",
+];
+
+/// Page size for paginated requests that don't specify `limit`
+pub(super) const DEFAULT_LIMIT: u32 = 20;
+/// Maximum page size
+pub(super) const MAX_LIMIT: u32 = 1000;
+/// Largest image that can be uploaded, in bytes
+pub const MAX_IMAGE_SIZE: usize = 2 * 1024 * 1024;
+/// Maximum length of a fish's name or species, in characters
+const MAX_NAME_LENGTH: usize = 100;
+/// Oldest allowed fish, in years. Some sharks live for centuries
+const MAX_AGE: u32 = 500;
+/// Heaviest allowed fish. The biggest whale sharks are around 20 tonnes
+const MAX_WEIGHT_KG: f64 = 25_000.0;
+/// Maximum number of operations in a bulk request
+const MAX_BULK_OPERATIONS: usize = 1000;
+/// Media type for newline-delimited JSON, used for import and export
+const NDJSON: &str = "application/x-ndjson";
+/// Number of imported fish to insert per transaction
+const IMPORT_BATCH_SIZE: usize = 500;
+/// Longest line accepted by an import, in bytes
+const MAX_IMPORT_LINE_LENGTH: usize = 64 * 1024;
+/// Maximum number of line errors included in an import response
+const MAX_IMPORT_ERRORS: usize = 100;
+
+/// List fish, with optional filtering, sorting, and pagination. Pagination
+/// is enabled by passing any of `limit`, `offset`, or `cursor`. If enabled,
+/// a `Link` header will be included with the URL of the next page (and
+/// previous/first pages for offset pagination). The response can be in any
+/// [Format].
+pub async fn list_fish(
+    store: SessionStore,
+    OriginalUri(uri): OriginalUri,
+    format: Format,
+    Query(query): Query<ListFishQuery>,
+) -> crate::Result<Response> {
+    store.authorize(Scope::FishRead)?;
+    if query.cursor.is_some() && query.offset.is_some() {
+        return Err(Error::InvalidQuery(
+            "`cursor` and `offset` cannot be used together".into(),
+        ));
+    }
+    if query
+        .limit
+        .is_some_and(|limit| !(1..=MAX_LIMIT).contains(&limit))
+    {
+        return Err(Error::InvalidQuery(format!(
+            "`limit` must be between 1 and {MAX_LIMIT}"
+        )));
+    }
+
+    let page = store.list(&query).await?;
+    let next_cursor = page
+        .has_more
+        .then(|| page.items.last())
+        .flatten()
+        .map(|fish| Cursor::after(&query.sort, fish));
 
-/// List fish
-pub async fn list_fish(store: SessionStore) -> crate::Result<Json<Vec<Fish>>> {
-    store.list().await.map(Json)
+    // Build links to other pages by modifying the current query
+    let mut links = Vec::new();
+    let mut link = |rel: &str, query: ListFishQuery| -> crate::Result<()> {
+        let query = serde_urlencoded::to_string(&query)
+            .map_err(|error| Error::InvalidQuery(error.to_string()))?;
+        links.push(format!("<{}?{query}>; rel=\"{rel}\"", uri.path()));
+        Ok(())
+    };
+    if let (Some(limit), Some(offset)) = (query.limit(), query.offset) {
+        // Offset pagination
+        if page.has_more {
+            link(
+                "next",
+                ListFishQuery {
+                    offset: Some(offset + limit),
+                    ..query.clone()
+                },
+            )?;
+        }
+        if offset > 0 {
+            link(
+                "prev",
+                ListFishQuery {
+                    offset: Some(offset.saturating_sub(limit)),
+                    ..query.clone()
+                },
+            )?;
+            link(
+                "first",
+                ListFishQuery {
+                    offset: Some(0),
+                    ..query.clone()
+                },
+            )?;
+        }
+    } else if let Some(cursor) = &next_cursor {
+        // Cursor pagination
+        link(
+            "next",
+            ListFishQuery {
+                cursor: Some(cursor.clone()),
+                limit: query.limit(),
+                ..query.clone()
+            },
+        )?;
+    }
+
+    let mut response = if query.envelope {
+        Encoded(
+            format,
+            FishListEnvelope {
+                items: page.items,
+                total: page.total,
+                next: next_cursor,
+            },
+        )
+        .into_response()
+    } else {
+        Encoded(format, page.items).into_response()
+    };
+    let headers = response.headers_mut();
+    headers.insert("X-Total-Count", page.total.into());
+    if !links.is_empty() {
+        headers.insert(
+            header::LINK,
+            HeaderValue::from_str(&links.join(", "))
+                .expect("Link header is URL-encoded"),
+        );
+    }
+    Ok(response)
 }
 
-/// Get a fish by ID
+/// Get a fish by ID. Supports conditional requests via `If-None-Match` and
+/// `If-Modified-Since`.
 pub async fn get_fish_by_id(
     store: SessionStore,
     Path(id): Path<FishId>,
-) -> crate::Result<Json<Fish>> {
-    store.get(id).await.map(Json)
+    conditions: Conditions,
+    format: Format,
+) -> crate::Result<Response> {
+    store.authorize(Scope::FishRead)?;
+    let fish = store.get(id).await?;
+    if conditions.is_not_modified(&fish) {
+        return Ok(not_modified(&fish, format));
+    }
+    Ok((validators(&fish, format), Encoded(format, fish)).into_response())
 }
 
-/// Create a new fish
+/// Create a new fish. The body can be in any [Format]
 pub async fn create_fish(
     store: SessionStore,
-    Json(body): Json<CreateFishRequest>,
-) -> crate::Result<Json<Fish>> {
-    store.create(body).await.map(Json)
+    format: Format,
+    Decoded(body): Decoded<CreateFishRequest>,
+) -> crate::Result<Response> {
+    store.authorize(Scope::FishWrite)?;
+    body.validate()?;
+    let fish = store.create(body).await?;
+    Ok((validators(&fish, format), Encoded(format, fish)).into_response())
 }
 
-/// Update an existing fish
+/// Replace all fields of a fish, or create it with the given ID if it
+/// doesn't exist. Supports optimistic concurrency via `If-Match` and
+/// `If-Unmodified-Since`, and `If-None-Match: *` to only allow creation.
+pub async fn replace_fish(
+    store: SessionStore,
+    Path(id): Path<FishId>,
+    OriginalUri(uri): OriginalUri,
+    conditions: Conditions,
+    format: Format,
+    Decoded(body): Decoded<CreateFishRequest>,
+) -> crate::Result<Response> {
+    store.authorize(Scope::FishWrite)?;
+    body.validate()?;
+    let (fish, created) = store.replace(id, body, &conditions).await?;
+    if created {
+        let location = HeaderValue::from_str(uri.path())
+            .expect("URI path is a valid header value");
+        Ok((
+            StatusCode::CREATED,
+            [(header::LOCATION, location)],
+            validators(&fish, format),
+            Encoded(format, fish),
+        )
+            .into_response())
+    } else {
+        Ok((validators(&fish, format), Encoded(format, fish)).into_response())
+    }
+}
+
+/// Update an existing fish. The body format is determined by `Content-Type`:
+/// - `application/merge-patch+json`: JSON Merge Patch (RFC 7396)
+/// - `application/json-patch+json`: JSON Patch (RFC 6902)
+/// - Any [Format]: An object of fields to update. Omitted fields are left
+///   unchanged
+///
+/// Supports optimistic concurrency via `If-Match` and `If-Unmodified-Since`.
 pub async fn update_fish(
     store: SessionStore,
     Path(id): Path<FishId>,
-    Json(body): Json<UpdateFishRequest>,
-) -> crate::Result<Json<Fish>> {
-    store.update(id, body).await.map(Json)
+    conditions: Conditions,
+    format: Format,
+    headers: HeaderMap,
+    body: Bytes,
+) -> crate::Result<Response> {
+    store.authorize(Scope::FishWrite)?;
+    let fish = match content_type(&headers).as_deref() {
+        Some("application/merge-patch+json") => {
+            let Json(patch) = Json::<serde_json::Value>::from_bytes(&body)?;
+            store
+                .modify(id, &conditions, move |fish| {
+                    apply_patch(fish, |document| {
+                        json_patch::merge(document, &patch);
+                        Ok(())
+                    })
+                })
+                .await?
+        }
+        Some("application/json-patch+json") => {
+            let Json(patch) = Json::<json_patch::Patch>::from_bytes(&body)?;
+            store
+                .modify(id, &conditions, move |fish| {
+                    apply_patch(fish, |document| {
+                        json_patch::patch(document, &patch)?;
+                        Ok(())
+                    })
+                })
+                .await?
+        }
+        _ => {
+            let body: UpdateFishRequest = Format::from_content_type(&headers)
+                .ok_or(Error::UnsupportedPatch)?
+                .decode(&body)?;
+            body.validate()?;
+            store.update(id, body, &conditions).await?
+        }
+    };
+    Ok((validators(&fish, format), Encoded(format, fish)).into_response())
 }
 
-/// Delete an existing fish
+/// Delete an existing fish. Supports optimistic concurrency via `If-Match` and
+/// `If-Unmodified-Since`.
 pub async fn delete_fish(
     store: SessionStore,
     Path(id): Path<FishId>,
-) -> crate::Result<Json<Fish>> {
-    store.delete(id).await.map(Json)
+    conditions: Conditions,
+    format: Format,
+) -> crate::Result<Encoded<Fish>> {
+    store.authorize(Scope::FishDelete)?;
+    let fish = store.delete(id, &conditions).await?;
+    Ok(Encoded(format, fish))
 }
 
-/// Request body for `POST /fish`
-#[derive(Debug, Deserialize)]
+/// Create, update, and delete many fish in a single transaction. By default
+/// the request is atomic: if any operation fails, none are applied and the
+/// failure is returned as the error. With `atomic=false`, each operation
+/// succeeds or fails on its own, and the response is a `207 Multi-Status`
+/// with a result per operation.
+pub async fn bulk_fish(
+    store: SessionStore,
+    Query(query): Query<BulkQuery>,
+    extract::Json(body): extract::Json<BulkRequest>,
+) -> crate::Result<Response> {
+    for operation in &body.operations {
+        store.authorize(operation.scope())?;
+    }
+    let atomic = query.atomic.unwrap_or(true);
+    let operations = if atomic {
+        // Report every invalid field up front, like any other request body
+        body.validate()?;
+        body.operations.into_iter().map(Ok).collect()
+    } else {
+        let mut validator = Validator::default();
+        check_operation_count(&mut validator, body.operations.len());
+        validator.finish()?;
+        body.operations
+            .into_iter()
+            .map(|operation| operation.validate().map(|()| operation))
+            .collect()
+    };
+
+    let results = store
+        .bulk(operations, atomic)
+        .await?
+        .into_iter()
+        .map(|result| match result {
+            // Same as each operation would get as its own request
+            Ok(fish) => BulkResult {
+                status: StatusCode::OK.as_u16(),
+                fish: Some(fish),
+                detail: None,
+                errors: Vec::new(),
+            },
+            Err(error) => {
+                error.log();
+                BulkResult {
+                    status: error.status().as_u16(),
+                    fish: None,
+                    detail: Some(error.detail()),
+                    errors: error.field_errors(),
+                }
+            }
+        })
+        .collect();
+    let status = if atomic {
+        StatusCode::OK
+    } else {
+        StatusCode::MULTI_STATUS
+    };
+    Ok((status, Json(BulkResponse { results })).into_response())
+}
+
+/// Export every fish in the session as newline-delimited JSON. The response is
+/// streamed straight from the database, so it works for any number of fish.
+pub async fn export_fish(store: SessionStore) -> crate::Result<Response> {
+    store.authorize(Scope::FishRead)?;
+    let lines = store.export().map(|fish| {
+        let mut line = serde_json::to_vec(&fish?)
+            .expect("Fish serialization is infallible");
+        line.push(b'\n');
+        Ok::<_, Error>(line)
+    });
+    Ok(([(header::CONTENT_TYPE, NDJSON)], Body::from_stream(lines))
+        .into_response())
+}
+
+/// Import fish from a newline-delimited JSON body, with one fish per line in
+/// the same format as `POST /fish`. The body is read incrementally and fish
+/// are inserted in batches, so it can be arbitrarily large. Invalid lines are
+/// skipped and reported by line number.
+pub async fn import_fish(
+    store: SessionStore,
+    headers: HeaderMap,
+    body: Body,
+) -> crate::Result<Json<ImportResponse>> {
+    store.authorize(Scope::FishWrite)?;
+    if !has_content_type(&headers, NDJSON) {
+        return Err(Error::UnsupportedMediaType {
+            accepted: vec![NDJSON],
+        });
+    }
+
+    let mut importer = Importer::new(store);
+    let mut lines = LineSplitter::default();
+    let mut chunks = body.into_data_stream();
+    while let Some(chunk) = chunks.next().await {
+        lines.push(&chunk.map_err(io::Error::other)?);
+        while let Some(line) = lines.next_line() {
+            importer.line(line).await?;
+        }
+    }
+    if let Some(line) = lines.finish() {
+        importer.line(line).await?;
+    }
+    importer.finish().await.map(Json)
+}
+
+/// Stream changes to this session's fish as Server-Sent Events. If the
+/// `Last-Event-ID` header is given, recent events after that ID are replayed
+/// first. When the session expires, a final `expired` event is sent and the
+/// stream is closed.
+pub async fn fish_events(
+    store: SessionStore,
+    headers: HeaderMap,
+) -> crate::Result<Sse<impl Stream<Item = Result<sse::Event, axum::Error>>>> {
+    store.authorize(Scope::FishRead)?;
+    let last_event_id = headers
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+    let events = store.subscribe(last_event_id)?.map(|event| {
+        let sse_event = sse::Event::default()
+            .id(event.id.to_string())
+            .event(event.kind.name());
+        match &event.kind {
+            EventKind::Created(fish)
+            | EventKind::Updated(fish)
+            | EventKind::Deleted(fish) => sse_event.json_data(fish),
+            EventKind::Expired => Ok(sse_event.data("Session expired")),
+        }
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Get a fish's image. The image will be converted to whichever supported
+/// format the `Accept` header prefers
+pub async fn get_image(
+    store: SessionStore,
+    Path(id): Path<FishId>,
+    headers: HeaderMap,
+) -> crate::Result<Response> {
+    store.authorize(Scope::FishRead)?;
+    let image = store.get_image(id).await?;
+
+    // Prefer the stored format so we don't convert unless the client asks
+    let available: Vec<&'static str> = iter::once(image.format)
+        .chain(
+            Image::FORMATS
+                .iter()
+                .copied()
+                .filter(|format| *format != image.format),
+        )
+        .map(|format| format.to_mime_type())
+        .collect();
+    let Some(content_type) = negotiate(&headers, &available) else {
+        return Err(Error::NotAcceptable { available });
+    };
+    let format = ImageFormat::from_mime_type(content_type)
+        .expect("Available types are all valid image formats");
+    // Converting is CPU-heavy, so keep it off the async runtime
+    let image = tokio::task::spawn_blocking(move || image.convert(format))
+        .await
+        .expect("Image conversion panicked")?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::VARY, header::ACCEPT.as_str()),
+        ],
+        image.data,
+    )
+        .into_response())
+}
+
+/// Upload an image for a fish, replacing the existing one. The body can be
+/// either `multipart/form-data` with an `image` field, or a raw `image/*` body.
+pub async fn upload_image(
+    store: SessionStore,
+    Path(id): Path<FishId>,
+    request: Request,
+) -> crate::Result<StatusCode> {
+    store.authorize(Scope::FishWrite)?;
+    let data = if has_content_type(request.headers(), "multipart/form-data") {
+        let mut multipart = Multipart::from_request(request, &()).await?;
+        let mut data = None;
+        while let Some(field) = multipart.next_field().await? {
+            if field.name() == Some("image") {
+                data = Some(field.bytes().await?);
+                break;
+            }
+        }
+        data.ok_or(Error::MissingImage)?
+    } else if content_type(request.headers())
+        .is_some_and(|content_type| content_type.starts_with("image/"))
+    {
+        Bytes::from_request(request, &()).await?
+    } else {
+        return Err(Error::UnsupportedImage);
+    };
+
+    // Decoding to validate the image is CPU-heavy, so keep it off the async
+    // runtime
+    let image = tokio::task::spawn_blocking(move || Image::new(data.into()))
+        .await
+        .expect("Image decoding panicked")?;
+    store.set_image(id, image).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Apply a patch to the JSON representation of a fish, and return the
+/// patched fields. Read-only fields can be used in tests, but not modified.
+fn apply_patch(
+    fish: &Fish,
+    patch: impl FnOnce(&mut serde_json::Value) -> crate::Result<()>,
+) -> crate::Result<CreateFishRequest> {
+    let original =
+        serde_json::to_value(fish).expect("Fish serialization is infallible");
+    let mut document = original.clone();
+    patch(&mut document)?;
+    let mut validator = Validator::default();
+    for field in ["id", "version", "updated_at"] {
+        validator.ensure(
+            field,
+            document.get(field) == original.get(field),
+            "read-only",
+        );
+    }
+    validator.finish()?;
+    let body: CreateFishRequest =
+        validate::from_value(document).map_err(|error| {
+            Error::Validation(vec![FieldError::from_serde(&error)])
+        })?;
+    body.validate()?;
+    Ok(body)
+}
+
+/// Error for an import line over [MAX_IMPORT_LINE_LENGTH]
+fn line_too_long() -> Error {
+    Error::InvalidBody {
+        format: Format::Json,
+        message: format!("line is longer than {MAX_IMPORT_LINE_LENGTH} bytes"),
+    }
+}
+
+/// Check the name or species of a fish
+fn check_name(validator: &mut Validator, field: &str, value: &str) {
+    validator.ensure(field, !value.trim().is_empty(), "must not be empty");
+    validator.ensure(
+        field,
+        value.chars().count() <= MAX_NAME_LENGTH,
+        &format!("must be at most {MAX_NAME_LENGTH} characters"),
+    );
+}
+
+fn check_age(validator: &mut Validator, age: u32) {
+    validator.ensure(
+        "age",
+        age <= MAX_AGE,
+        &format!("must be at most {MAX_AGE}"),
+    );
+}
+
+fn check_weight(validator: &mut Validator, weight_kg: f64) {
+    validator.ensure(
+        "weight_kg",
+        weight_kg > 0.0 && weight_kg <= MAX_WEIGHT_KG,
+        &format!("must be greater than 0 and at most {MAX_WEIGHT_KG}"),
+    );
+}
+
+fn check_operation_count(validator: &mut Validator, count: usize) {
+    validator.ensure(
+        "operations",
+        (1..=MAX_BULK_OPERATIONS).contains(&count),
+        &format!("must have between 1 and {MAX_BULK_OPERATIONS} items"),
+    );
+}
+
+/// Query parameters for `GET /fish`
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ListFishQuery {
+    /// Only include fish of this species (case-insensitive)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub species: Option<String>,
+    /// Only include fish whose name contains this string (case-insensitive)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name_contains: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_age: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_weight_kg: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_weight_kg: Option<f64>,
+    /// Sort order, e.g. `age,-weight_kg`. Defaults to ascending ID
+    #[serde(default, skip_serializing_if = "Sort::is_empty")]
+    pub sort: Sort,
+    /// Maximum number of fish to return
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    /// Number of fish to skip, for offset pagination
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u32>,
+    /// Position to start after, for cursor pagination
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<Cursor>,
+    /// Wrap the response in an object with pagination metadata
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub envelope: bool,
+}
+
+impl ListFishQuery {
+    /// Page size. If any pagination parameter is given but `limit` isn't, use
+    /// a default. If none are given, pagination is disabled.
+    pub fn limit(&self) -> Option<u32> {
+        if self.limit.is_some() {
+            self.limit
+        } else if self.offset.is_some() || self.cursor.is_some() {
+            Some(DEFAULT_LIMIT)
+        } else {
+            None
+        }
+    }
+}
+
+/// Response body for `GET /fish?envelope=true`
+#[derive(Debug, Serialize)]
+pub struct FishListEnvelope {
+    pub items: Vec<Fish>,
+    /// Total number of fish matching the filters, across all pages
+    pub total: u64,
+    /// Cursor for the next page, or `null` if this is the last page
+    pub next: Option<Cursor>,
+}
+
+/// CSV column names for [Fish], for lists with no records to take them from
+const FISH_CSV_HEADER: [&str; 7] = [
+    "id",
+    "name",
+    "species",
+    "age",
+    "weight_kg",
+    "version",
+    "updated_at",
+];
+
+impl Encode for Fish {
+    fn to_xml(&self) -> Result<String, quick_xml::SeError> {
+        quick_xml::se::to_string_with_root("fish", self)
+    }
+
+    fn write_csv(
+        &self,
+        writer: &mut csv::Writer<Vec<u8>>,
+    ) -> Result<(), csv::Error> {
+        writer.serialize(self)
+    }
+}
+
+impl Encode for Vec<Fish> {
+    fn to_xml(&self) -> Result<String, quick_xml::SeError> {
+        /// XML needs a single root element, with one child per fish
+        #[derive(Serialize)]
+        struct FishList<'a> {
+            fish: &'a [Fish],
+        }
+        quick_xml::se::to_string_with_root(
+            "fish_list",
+            &FishList { fish: self },
+        )
+    }
+
+    fn write_csv(
+        &self,
+        writer: &mut csv::Writer<Vec<u8>>,
+    ) -> Result<(), csv::Error> {
+        // The header comes from the first record, so write it ourselves when
+        // there are none. Clients can still tell which columns to expect.
+        if self.is_empty() {
+            return writer.write_record(FISH_CSV_HEADER);
+        }
+        self.iter().try_for_each(|fish| writer.serialize(fish))
+    }
+}
+
+impl Encode for FishListEnvelope {
+    fn to_xml(&self) -> Result<String, quick_xml::SeError> {
+        quick_xml::se::to_string_with_root("page", self)
+    }
+
+    /// CSV can't hold the pagination metadata, so just write the items. The
+    /// same metadata is in the `X-Total-Count` and `Link` headers.
+    fn write_csv(
+        &self,
+        writer: &mut csv::Writer<Vec<u8>>,
+    ) -> Result<(), csv::Error> {
+        self.items.write_csv(writer)
+    }
+}
+
+/// Request body for `POST /fish` and `PUT /fish/{id}`
+#[derive(Debug, Deserialize, Serialize)]
 pub struct CreateFishRequest {
     pub name: String,
     pub species: String,
@@ -59,6 +695,15 @@ pub struct CreateFishRequest {
     pub weight_kg: f64,
 }
 
+impl Validate for CreateFishRequest {
+    fn check(&self, validator: &mut Validator) {
+        check_name(validator, "name", &self.name);
+        check_name(validator, "species", &self.species);
+        check_age(validator, self.age);
+        check_weight(validator, self.weight_kg);
+    }
+}
+
 /// Request body for `PATCH /fish`
 #[derive(Debug, Deserialize)]
 pub struct UpdateFishRequest {
@@ -68,9 +713,577 @@ pub struct UpdateFishRequest {
     pub weight_kg: Option<f64>,
 }
 
-/// Response body for `POST /login`
+impl Validate for UpdateFishRequest {
+    fn check(&self, validator: &mut Validator) {
+        if let Some(name) = &self.name {
+            check_name(validator, "name", name);
+        }
+        if let Some(species) = &self.species {
+            check_name(validator, "species", species);
+        }
+        if let Some(age) = self.age {
+            check_age(validator, age);
+        }
+        if let Some(weight_kg) = self.weight_kg {
+            check_weight(validator, weight_kg);
+        }
+    }
+}
+
+/// Query parameters for `POST /fish/bulk`
+#[derive(Debug, Deserialize)]
+pub struct BulkQuery {
+    /// Apply all operations or none of them. Defaults to `true`
+    pub atomic: Option<bool>,
+}
+
+/// Request body for `POST /fish/bulk`
+#[derive(Debug, Deserialize)]
+pub struct BulkRequest {
+    pub operations: Vec<BulkOperation>,
+}
+
+impl Validate for BulkRequest {
+    fn check(&self, validator: &mut Validator) {
+        check_operation_count(validator, self.operations.len());
+        for (index, operation) in self.operations.iter().enumerate() {
+            validator.ensure_item("operations", index, operation);
+        }
+    }
+}
+
+/// A single change in a bulk request, tagged by `op`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BulkOperation {
+    Create { fish: CreateFishRequest },
+    Update { id: FishId, fish: UpdateFishRequest },
+    Delete { id: FishId },
+}
+
+impl BulkOperation {
+    /// Scope needed to perform the operation
+    fn scope(&self) -> Scope {
+        match self {
+            Self::Create { .. } | Self::Update { .. } => Scope::FishWrite,
+            Self::Delete { .. } => Scope::FishDelete,
+        }
+    }
+}
+
+impl Validate for BulkOperation {
+    fn check(&self, validator: &mut Validator) {
+        match self {
+            Self::Create { fish } => validator.ensure_field("fish", fish),
+            Self::Update { fish, .. } => validator.ensure_field("fish", fish),
+            Self::Delete { .. } => {}
+        }
+    }
+}
+
+/// Response body for `POST /fish/bulk`
+#[derive(Debug, Serialize)]
+pub struct BulkResponse {
+    /// One result per operation, in request order
+    pub results: Vec<BulkResult>,
+}
+
+/// Outcome of a single operation in a bulk request
 #[derive(Debug, Serialize)]
-pub struct LoginResponse {
-    pub id: SessionId,
-    pub expires_at: String,
+pub struct BulkResult {
+    /// Status the operation would have gotten as its own request
+    pub status: u16,
+    /// The created, updated, or deleted fish, if successful
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fish: Option<Fish>,
+    /// Error message, if the operation failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// Invalid fields in the operation, if any
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+/// Collects fish from an import body and inserts them in batches
+struct Importer {
+    store: SessionStore,
+    batch: Vec<CreateFishRequest>,
+    response: ImportResponse,
+}
+
+impl Importer {
+    fn new(store: SessionStore) -> Self {
+        Self {
+            store,
+            batch: Vec::with_capacity(IMPORT_BATCH_SIZE),
+            response: ImportResponse {
+                imported: 0,
+                failed: 0,
+                errors: Vec::new(),
+            },
+        }
+    }
+
+    /// Parse one line of the body. Blank lines are ignored
+    async fn line(&mut self, line: Line<'_>) -> crate::Result<()> {
+        let (number, line) = match line {
+            Line::Complete(number, line) => (number, line),
+            Line::TooLong(number) => {
+                self.fail(number, line_too_long());
+                return Ok(());
+            }
+        };
+        if line.trim_ascii().is_empty() {
+            return Ok(());
+        }
+        let parsed = Format::Json
+            .decode::<CreateFishRequest>(line)
+            .and_then(|fish| fish.validate().map(|()| fish));
+        match parsed {
+            Ok(fish) => {
+                self.batch.push(fish);
+                if self.batch.len() >= IMPORT_BATCH_SIZE {
+                    self.flush().await?;
+                }
+            }
+            Err(error) => self.fail(number, error),
+        }
+        Ok(())
+    }
+
+    /// Record an invalid line
+    fn fail(&mut self, number: usize, error: Error) {
+        error.log();
+        self.response.failed += 1;
+        if self.response.errors.len() < MAX_IMPORT_ERRORS {
+            self.response.errors.push(ImportError {
+                line: number,
+                detail: error.detail(),
+                errors: error.field_errors(),
+            });
+        }
+    }
+
+    /// Insert the current batch in a single transaction
+    async fn flush(&mut self) -> crate::Result<()> {
+        let operations = self
+            .batch
+            .drain(..)
+            .map(|fish| Ok(BulkOperation::Create { fish }))
+            .collect();
+        let created = self.store.bulk(operations, true).await?;
+        self.response.imported += created.len();
+        Ok(())
+    }
+
+    /// Insert any remaining fish and return the results
+    async fn finish(mut self) -> crate::Result<ImportResponse> {
+        if !self.batch.is_empty() {
+            self.flush().await?;
+        }
+        Ok(self.response)
+    }
+}
+
+/// Splits an import body into lines as its chunks arrive. Consumed lines are
+/// only dropped from the buffer when the next chunk comes in, and the search
+/// for a newline picks up where it left off, so each byte is handled a fixed
+/// number of times however the body is chunked.
+#[derive(Debug, Default)]
+struct LineSplitter {
+    buffer: Vec<u8>,
+    /// Start of the current line in the buffer
+    start: usize,
+    /// Everything before this has been searched for a newline
+    searched: usize,
+    /// Number of lines finished so far
+    count: usize,
+    /// Whether the current line was too long, and is being discarded
+    overlong: bool,
+}
+
+/// A line of an import body, with its number, starting from 1
+#[derive(Debug, PartialEq)]
+enum Line<'a> {
+    /// A full line, without the `\n` or `\r\n`
+    Complete(usize, &'a [u8]),
+    /// A line over [MAX_IMPORT_LINE_LENGTH]. This is reported as soon as it's
+    /// too long, and the rest of the line is skipped rather than buffered.
+    TooLong(usize),
+}
+
+impl LineSplitter {
+    /// Add the next chunk of the body
+    fn push(&mut self, chunk: &[u8]) {
+        self.buffer.drain(..self.start);
+        self.searched -= self.start;
+        self.start = 0;
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    /// Get the next line from the chunks so far. Return `None` if the rest of
+    /// the buffer is an incomplete line.
+    fn next_line(&mut self) -> Option<Line<'_>> {
+        loop {
+            let Some(offset) = self.buffer[self.searched..]
+                .iter()
+                .position(|&byte| byte == b'\n')
+            else {
+                self.searched = self.buffer.len();
+                if self.overlong {
+                    self.buffer.clear();
+                    self.start = 0;
+                    self.searched = 0;
+                } else if line_length(&self.buffer[self.start..])
+                    > MAX_IMPORT_LINE_LENGTH
+                {
+                    // Report it now so the rest doesn't need buffering
+                    self.overlong = true;
+                    return Some(Line::TooLong(self.count + 1));
+                }
+                return None;
+            };
+            let end = self.searched + offset;
+            let start = mem::replace(&mut self.start, end + 1);
+            self.searched = self.start;
+            self.count += 1;
+            // Overlong lines were already reported
+            if !mem::take(&mut self.overlong) {
+                return Some(Line::new(self.count, &self.buffer[start..end]));
+            }
+        }
+    }
+
+    /// Get the last line once the body is done. It doesn't need a trailing
+    /// newline
+    fn finish(&mut self) -> Option<Line<'_>> {
+        let line = &self.buffer[self.start..];
+        (!line.is_empty() && !self.overlong)
+            .then(|| Line::new(self.count + 1, line))
+    }
+}
+
+impl<'a> Line<'a> {
+    /// Wrap a full line of the body, minus the `\n`
+    fn new(number: usize, line: &'a [u8]) -> Self {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.len() > MAX_IMPORT_LINE_LENGTH {
+            Self::TooLong(number)
+        } else {
+            Self::Complete(number, line)
+        }
+    }
+}
+
+/// Length of a line without its `\r`, if any. For a partial line, the `\r` may
+/// be the start of a `\r\n`.
+fn line_length(line: &[u8]) -> usize {
+    line.strip_suffix(b"\r").unwrap_or(line).len()
+}
+
+/// Response body for `POST /fish/import`
+#[derive(Debug, Serialize)]
+pub struct ImportResponse {
+    /// Number of fish created
+    pub imported: usize,
+    /// Number of lines that were skipped because they were invalid
+    pub failed: usize,
+    /// Details of the first invalid lines
+    pub errors: Vec<ImportError>,
+}
+
+/// An invalid line in an import body
+#[derive(Debug, Serialize)]
+pub struct ImportError {
+    /// Line number, starting from 1
+    pub line: usize,
+    pub detail: String,
+    /// Invalid fields in the line, if known
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data::Store, scope::Scopes};
+    use axum::body;
+    use jiff::Timestamp;
+    use serde_json::{Value, json};
+
+    async fn bulk(
+        session: &SessionStore,
+        atomic: bool,
+        operations: Value,
+    ) -> crate::Result<(StatusCode, Value)> {
+        let body = serde_json::from_value(json!({ "operations": operations }))
+            .unwrap();
+        let response = bulk_fish(
+            session.clone(),
+            Query(BulkQuery {
+                atomic: Some(atomic),
+            }),
+            extract::Json(body),
+        )
+        .await?;
+        let status = response.status();
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        Ok((status, serde_json::from_slice(&body).unwrap()))
+    }
+
+    /// Feed a body to a [LineSplitter] in chunks of the given size. Return
+    /// each line's number and content, or `None` if it's too long.
+    fn split(body: &[u8], chunk_size: usize) -> Vec<(usize, Option<String>)> {
+        fn owned(line: Line<'_>) -> (usize, Option<String>) {
+            match line {
+                Line::Complete(number, line) => {
+                    (number, Some(String::from_utf8(line.to_vec()).unwrap()))
+                }
+                Line::TooLong(number) => (number, None),
+            }
+        }
+
+        let mut lines = LineSplitter::default();
+        let mut split = Vec::new();
+        for chunk in body.chunks(chunk_size) {
+            lines.push(chunk);
+            while let Some(line) = lines.next_line() {
+                split.push(owned(line));
+            }
+        }
+        split.extend(lines.finish().map(owned));
+        split
+    }
+
+    /// Chunk boundaries can fall anywhere, including between `\r` and `\n`
+    #[test]
+    fn split_lines() {
+        let body = b"a\r\nbb\n\n\r\nccc";
+        let expected = [
+            (1, Some("a".to_owned())),
+            (2, Some("bb".to_owned())),
+            (3, Some("".to_owned())),
+            (4, Some("".to_owned())),
+            (5, Some("ccc".to_owned())),
+        ];
+        for chunk_size in 1..=body.len() {
+            assert_eq!(split(body, chunk_size), expected, "{chunk_size}");
+        }
+        // A trailing newline doesn't start another line
+        assert_eq!(split(b"a\n", 1), [(1, Some("a".to_owned()))]);
+        assert_eq!(split(b"", 1), []);
+    }
+
+    /// Overlong lines are reported once, whether or not they arrive in one
+    /// piece, and the lines after them keep their numbers
+    #[test]
+    fn split_overlong_lines() {
+        let longest = "x".repeat(MAX_IMPORT_LINE_LENGTH);
+        let too_long = "y".repeat(MAX_IMPORT_LINE_LENGTH + 1);
+        let body = format!(
+            "a\n{too_long}\n{longest}\r\nb\n{too_long}\r\nc\n{too_long}"
+        );
+        let expected = [
+            (1, Some("a".to_owned())),
+            (2, None),
+            (3, Some(longest.clone())),
+            (4, Some("b".to_owned())),
+            (5, None),
+            (6, Some("c".to_owned())),
+            (7, None),
+        ];
+        for chunk_size in [1, 1000, MAX_IMPORT_LINE_LENGTH + 2, body.len()] {
+            assert_eq!(
+                split(body.as_bytes(), chunk_size),
+                expected,
+                "{chunk_size}"
+            );
+        }
+    }
+
+    /// Import through the handler, with the body in uneven chunks
+    #[tokio::test]
+    async fn import_chunked() {
+        let store = Store::test(0);
+        let session = store.test_session(Scopes::all()).await;
+        let before = count(&session).await;
+        let fish =
+            r#"{"name":"Dory","species":"Blue Tang","age":3,"weight_kg":0.3}"#;
+        let mut body = format!("{fish}\r\n\n{{}}\n").into_bytes();
+        for _ in 0..IMPORT_BATCH_SIZE {
+            body.extend_from_slice(format!("{fish}\n").as_bytes());
+        }
+        body.extend_from_slice(fish.as_bytes());
+        let chunks: Vec<Result<Vec<u8>, io::Error>> =
+            body.chunks(37).map(|chunk| Ok(chunk.to_vec())).collect();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(NDJSON));
+
+        let Json(response) = import_fish(
+            session.clone(),
+            headers,
+            Body::from_stream(futures::stream::iter(chunks)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.imported, IMPORT_BATCH_SIZE + 2);
+        assert_eq!(response.failed, 1);
+        assert_eq!(response.errors[0].line, 3);
+        let total = before + IMPORT_BATCH_SIZE as u64 + 2;
+        assert_eq!(count(&session).await, total);
+    }
+
+    /// Fish are inserted as soon as there are [IMPORT_BATCH_SIZE] of them,
+    /// and the rest at the end
+    #[tokio::test]
+    async fn import_batches() {
+        let store = Store::test(0);
+        let session = store.test_session(Scopes::all()).await;
+        let before = count(&session).await;
+        let fish =
+            br#"{"name":"Dory","species":"Blue Tang","age":3,"weight_kg":0.3}"#;
+        let mut importer = Importer::new(session.clone());
+        let mut number = 0;
+        let mut import = async |importer: &mut Importer, lines| {
+            for _ in 0..lines {
+                number += 1;
+                importer.line(Line::Complete(number, fish)).await.unwrap();
+            }
+        };
+
+        import(&mut importer, IMPORT_BATCH_SIZE - 1).await;
+        assert_eq!(count(&session).await, before);
+        import(&mut importer, 1).await;
+        let batch = IMPORT_BATCH_SIZE as u64;
+        assert_eq!(count(&session).await, before + batch);
+        import(&mut importer, 2).await;
+        assert_eq!(count(&session).await, before + batch);
+
+        let response = importer.finish().await.unwrap();
+        assert_eq!(response.imported, IMPORT_BATCH_SIZE + 2);
+        assert_eq!(count(&session).await, before + batch + 2);
+    }
+
+    async fn count(session: &SessionStore) -> u64 {
+        session.list(&ListFishQuery::default()).await.unwrap().total
+    }
+
+    /// A failed operation in an atomic batch undoes the ones before it
+    #[tokio::test]
+    async fn bulk_atomic_rollback() {
+        let store = Store::test(0);
+        let session = store.test_session(Scopes::all()).await;
+        let before = count(&session).await;
+        let operations = json!([
+            {"op": "create", "fish": {
+                "name": "Dory", "species": "Blue Tang", "age": 3,
+                "weight_kg": 0.3,
+            }},
+            {"op": "update", "id": 1, "fish": {"age": 4}},
+            {"op": "delete", "id": 999},
+            {"op": "delete", "id": 2},
+        ]);
+
+        let error = bulk(&session, true, operations).await.unwrap_err();
+        assert!(
+            matches!(error, Error::BulkOperation { index: 2, .. }),
+            "{error:?}"
+        );
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+        assert_eq!(count(&session).await, before);
+        assert_eq!(session.get(FishId(1)).await.unwrap().version, 1);
+    }
+
+    /// Without atomic mode, each operation succeeds or fails on its own
+    #[tokio::test]
+    async fn bulk_mixed_results() {
+        let store = Store::test(0);
+        let session = store.test_session(Scopes::all()).await;
+        let before = count(&session).await;
+        let operations = json!([
+            {"op": "create", "fish": {
+                "name": "Dory", "species": "Blue Tang", "age": 3,
+                "weight_kg": 0.3,
+            }},
+            {"op": "create", "fish": {
+                "name": "", "species": "Blue Tang", "age": 3,
+                "weight_kg": 0.3,
+            }},
+            {"op": "delete", "id": 999},
+            {"op": "delete", "id": 2},
+        ]);
+
+        let (status, body) = bulk(&session, false, operations).await.unwrap();
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        let results = body["results"].as_array().unwrap();
+        let statuses: Vec<_> =
+            results.iter().map(|result| &result["status"]).collect();
+        assert_eq!(statuses, [200, 422, 404, 200]);
+        assert_eq!(results[0]["fish"]["name"], "Dory");
+        assert_eq!(results[1]["errors"][0]["field"], "fish.name");
+        assert_eq!(results[3]["fish"]["id"], 2);
+        // One created and one deleted
+        assert_eq!(count(&session).await, before);
+        assert!(session.get(FishId(2)).await.is_err());
+    }
+
+    /// Every operation needs its own scope, and one missing scope rejects the
+    /// whole batch before anything is applied, even when not atomic
+    #[tokio::test]
+    async fn bulk_scope_per_operation() {
+        let store = Store::test(0);
+        let scope = Scopes::from_iter([Scope::FishRead, Scope::FishWrite]);
+        let session = store.test_session(scope).await;
+        let before = count(&session).await;
+        let operations = json!([
+            {"op": "create", "fish": {
+                "name": "Dory", "species": "Blue Tang", "age": 3,
+                "weight_kg": 0.3,
+            }},
+            {"op": "delete", "id": 2},
+        ]);
+
+        for atomic in [true, false] {
+            let error = bulk(&session, atomic, operations.clone())
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(
+                    error,
+                    Error::Forbidden {
+                        required: Scope::FishDelete
+                    }
+                ),
+                "{error:?}"
+            );
+        }
+        assert_eq!(count(&session).await, before);
+
+        // Operations that are allowed still go through
+        let (_, body) =
+            bulk(&session, false, json!([operations[0]])).await.unwrap();
+        assert_eq!(body["results"][0]["status"], 200);
+    }
+
+    /// An empty list still gets a header row, and it matches the one written
+    /// for a list with fish in it
+    #[test]
+    fn csv_header() {
+        let fish = Fish {
+            key: 1,
+            id: FishId(1),
+            name: "Nemo".to_owned(),
+            species: "Clownfish".to_owned(),
+            age: 1,
+            weight_kg: 0.2,
+            version: 1,
+            updated_at: Timestamp::UNIX_EPOCH,
+        };
+        let empty = String::from_utf8(Format::Csv.encode(&Vec::new())).unwrap();
+        let full = String::from_utf8(Format::Csv.encode(&vec![fish])).unwrap();
+        assert_eq!(empty, format!("{}\n", FISH_CSV_HEADER.join(",")));
+        assert_eq!(full.lines().next(), empty.lines().next());
+    }
 }
@@ -0,0 +1,655 @@
+//! GraphQL API over the fish store. It offers the same operations as the REST
+//! routes, with the same authentication and scopes.
+
+use crate::{
+    Error,
+    conditional::Conditions,
+    data::{Cursor, Fish, FishId, SessionStore},
+    events::{Event, EventKind},
+    extract,
+    format::Format,
+    routes::{
+        CreateFishRequest, ListFishQuery, UpdateFishRequest,
+        fish::{DEFAULT_LIMIT, MAX_LIMIT},
+    },
+    scope::Scope,
+    validate::Validate,
+};
+use async_graphql::{
+    BatchRequest, BatchResponse, Context, Data, Enum, InputObject, Object,
+    Schema, ServerError, ServerResult, SimpleObject, Subscription, Variables,
+    extensions::{
+        Extension as GraphQLExtension, ExtensionContext, ExtensionFactory,
+        NextParseQuery,
+    },
+    http::{
+        ALL_WEBSOCKET_PROTOCOLS, GraphiQLSource, WebSocketProtocols, WsMessage,
+    },
+    parser::types::{ExecutableDocument, Selection, SelectionSet},
+};
+use axum::{
+    Extension, Json,
+    extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    http::{HeaderMap, header},
+    response::{Html, Response},
+};
+use bytes::Bytes;
+use futures::{SinkExt, Stream, StreamExt, future};
+use std::{collections::HashMap, sync::Arc};
+
+/// Deepest allowed field nesting. Fish queries only need a few levels, but
+/// GraphiQL's introspection query goes deeper.
+const MAX_DEPTH: usize = 15;
+/// Most expensive allowed query. Each field costs 1, and a page of fish costs
+/// its size times its fields, so this allows a full page with every field.
+const MAX_COMPLEXITY: usize = 20_000;
+/// Maximum number of aliased fields in a query, counting fragments each time
+/// they're used
+const MAX_ALIASES: usize = 50;
+/// Maximum number of requests in a batch
+const MAX_BATCH_SIZE: usize = 20;
+
+pub type FishSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+
+/// Build the GraphQL schema. The session is attached to each request, so the
+/// schema itself is stateless.
+pub fn graphql_schema() -> FishSchema {
+    Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .extension(AliasLimit)
+        .finish()
+}
+
+/// Execute a GraphQL request, or a batch of them
+pub async fn graphql(
+    Extension(schema): Extension<FishSchema>,
+    store: SessionStore,
+    extract::Json(request): extract::Json<BatchRequest>,
+) -> crate::Result<Json<BatchResponse>> {
+    if let BatchRequest::Batch(requests) = &request
+        && requests.len() > MAX_BATCH_SIZE
+    {
+        return Err(Error::InvalidBody {
+            format: Format::Json,
+            message: format!(
+                "batch must have at most {MAX_BATCH_SIZE} requests"
+            ),
+        });
+    }
+    Ok(Json(schema.execute_batch(request.data(store)).await))
+}
+
+/// Run GraphQL subscriptions over a WebSocket. Both the `graphql-transport-ws`
+/// and legacy `graphql-ws` protocols are supported, and the newer one is
+/// assumed if the client doesn't ask for either.
+pub async fn graphql_ws(
+    Extension(schema): Extension<FishSchema>,
+    store: SessionStore,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let protocol = headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|protocol| protocol.trim().parse().ok())
+        .unwrap_or(WebSocketProtocols::GraphQLWS);
+    upgrade
+        .protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |socket| {
+            handle_graphql_ws(socket, schema, store, protocol)
+        })
+}
+
+/// In-browser IDE for the GraphQL API
+pub async fn graphiql() -> Html<String> {
+    Html(
+        GraphiQLSource::build()
+            .endpoint("/graphql")
+            .subscription_endpoint("/graphql/ws")
+            .title("Shoal GraphiQL")
+            .finish(),
+    )
+}
+
+async fn handle_graphql_ws(
+    socket: WebSocket,
+    schema: FishSchema,
+    store: SessionStore,
+    protocol: WebSocketProtocols,
+) {
+    let (mut sink, stream) = socket.split();
+    // Pings are answered automatically, and the stream ends on close
+    let input = stream
+        .take_while(|message| {
+            future::ready(!matches!(message, Err(_) | Ok(Message::Close(_))))
+        })
+        .filter_map(|message| {
+            future::ready(match message {
+                Ok(Message::Text(text)) => Some(Bytes::from(text)),
+                Ok(Message::Binary(bytes)) => Some(bytes),
+                _ => None,
+            })
+        });
+    let mut data = Data::default();
+    data.insert(store);
+    let mut output =
+        async_graphql::http::WebSocket::new(schema, input, protocol)
+            .connection_data(data);
+    while let Some(message) = output.next().await {
+        let message = match message {
+            WsMessage::Text(text) => Message::Text(text.into()),
+            WsMessage::Close(code, reason) => {
+                Message::Close(Some(CloseFrame {
+                    code,
+                    reason: reason.into(),
+                }))
+            }
+        };
+        if sink.send(message).await.is_err() {
+            break;
+        }
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Get a fish by ID, or `null` if it doesn't exist
+    async fn fish(
+        &self,
+        ctx: &Context<'_>,
+        id: u32,
+    ) -> async_graphql::Result<Option<FishObject>> {
+        let store = ctx.data::<SessionStore>()?;
+        store.authorize(Scope::FishRead)?;
+        match store.get(FishId(id)).await {
+            Ok(fish) => Ok(Some(FishObject(fish))),
+            Err(Error::NotFound) => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// List fish matching a filter, in ID order. Pass the `endCursor` of one
+    /// page as `after` to get the next.
+    #[graphql(complexity = "first.unwrap_or(DEFAULT_LIMIT) as usize \
+        * child_complexity")]
+    async fn fishes(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<FishFilter>,
+        #[graphql(desc = "Page size. Defaults to 20")] first: Option<u32>,
+        after: Option<String>,
+    ) -> async_graphql::Result<FishConnection> {
+        let store = ctx.data::<SessionStore>()?;
+        store.authorize(Scope::FishRead)?;
+        let limit = first.unwrap_or(DEFAULT_LIMIT);
+        if !(1..=MAX_LIMIT).contains(&limit) {
+            return Err(Error::InvalidQuery(format!(
+                "`first` must be between 1 and {MAX_LIMIT}"
+            ))
+            .into());
+        }
+        let filter = filter.unwrap_or_default();
+        let query = ListFishQuery {
+            species: filter.species,
+            name_contains: filter.name_contains,
+            min_age: filter.min_age,
+            max_age: filter.max_age,
+            min_weight_kg: filter.min_weight_kg,
+            max_weight_kg: filter.max_weight_kg,
+            limit: Some(limit),
+            cursor: after.map(Cursor::try_from).transpose()?,
+            ..ListFishQuery::default()
+        };
+
+        let page = store.list(&query).await?;
+        let edges: Vec<FishEdge> = page
+            .items
+            .into_iter()
+            .map(|fish| FishEdge {
+                cursor: Cursor::after(&query.sort, &fish).into(),
+                node: FishObject(fish),
+            })
+            .collect();
+        Ok(FishConnection {
+            page_info: PageInfo {
+                has_next_page: page.has_more,
+                end_cursor: edges.last().map(|edge| edge.cursor.clone()),
+            },
+            edges,
+            total_count: page.total,
+        })
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    async fn create_fish(
+        &self,
+        ctx: &Context<'_>,
+        input: CreateFishInput,
+    ) -> async_graphql::Result<FishObject> {
+        let store = ctx.data::<SessionStore>()?;
+        store.authorize(Scope::FishWrite)?;
+        let body = CreateFishRequest::from(input);
+        body.validate()?;
+        Ok(FishObject(store.create(body).await?))
+    }
+
+    /// Update the given fields of a fish. Omitted fields are left unchanged
+    async fn update_fish(
+        &self,
+        ctx: &Context<'_>,
+        id: u32,
+        input: UpdateFishInput,
+    ) -> async_graphql::Result<FishObject> {
+        let store = ctx.data::<SessionStore>()?;
+        store.authorize(Scope::FishWrite)?;
+        let body = UpdateFishRequest::from(input);
+        body.validate()?;
+        let fish = store
+            .update(FishId(id), body, &Conditions::default())
+            .await?;
+        Ok(FishObject(fish))
+    }
+
+    /// Delete a fish, returning it
+    async fn delete_fish(
+        &self,
+        ctx: &Context<'_>,
+        id: u32,
+    ) -> async_graphql::Result<FishObject> {
+        let store = ctx.data::<SessionStore>()?;
+        store.authorize(Scope::FishDelete)?;
+        let fish = store.delete(FishId(id), &Conditions::default()).await?;
+        Ok(FishObject(fish))
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Changes to fish in this session, including changes made by other
+    /// clients. The stream ends when the session expires.
+    async fn fish_events(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<impl Stream<Item = FishEvent> + use<>> {
+        let store = ctx.data::<SessionStore>()?;
+        store.authorize(Scope::FishRead)?;
+        Ok(store.subscribe(None)?.map(FishEvent::from))
+    }
+}
+
+/// GraphQL view of a [Fish]
+struct FishObject(Fish);
+
+#[Object(name = "Fish")]
+impl FishObject {
+    async fn id(&self) -> u32 {
+        self.0.id.0
+    }
+
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    async fn species(&self) -> &str {
+        &self.0.species
+    }
+
+    async fn age(&self) -> u32 {
+        self.0.age
+    }
+
+    async fn weight_kg(&self) -> f64 {
+        self.0.weight_kg
+    }
+
+    /// Incremented on every modification
+    async fn version(&self) -> u32 {
+        self.0.version
+    }
+
+    /// Time of the last modification, in RFC 3339 format
+    async fn updated_at(&self) -> String {
+        self.0.updated_at.to_string()
+    }
+}
+
+/// Filters for `fishes`, with the same meaning as the `GET /fish` query
+#[derive(Debug, Default, InputObject)]
+struct FishFilter {
+    /// Only include fish of this species (case-insensitive)
+    species: Option<String>,
+    /// Only include fish whose name contains this string (case-insensitive)
+    name_contains: Option<String>,
+    min_age: Option<u32>,
+    max_age: Option<u32>,
+    min_weight_kg: Option<f64>,
+    max_weight_kg: Option<f64>,
+}
+
+#[derive(Debug, InputObject)]
+struct CreateFishInput {
+    name: String,
+    species: String,
+    age: u32,
+    weight_kg: f64,
+}
+
+impl From<CreateFishInput> for CreateFishRequest {
+    fn from(input: CreateFishInput) -> Self {
+        Self {
+            name: input.name,
+            species: input.species,
+            age: input.age,
+            weight_kg: input.weight_kg,
+        }
+    }
+}
+
+#[derive(Debug, InputObject)]
+struct UpdateFishInput {
+    name: Option<String>,
+    species: Option<String>,
+    age: Option<u32>,
+    weight_kg: Option<f64>,
+}
+
+impl From<UpdateFishInput> for UpdateFishRequest {
+    fn from(input: UpdateFishInput) -> Self {
+        Self {
+            name: input.name,
+            species: input.species,
+            age: input.age,
+            weight_kg: input.weight_kg,
+        }
+    }
+}
+
+/// A page of fish
+#[derive(SimpleObject)]
+struct FishConnection {
+    edges: Vec<FishEdge>,
+    page_info: PageInfo,
+    /// Total number of fish matching the filter, across all pages
+    total_count: u64,
+}
+
+#[derive(SimpleObject)]
+struct FishEdge {
+    cursor: String,
+    node: FishObject,
+}
+
+#[derive(SimpleObject)]
+struct PageInfo {
+    has_next_page: bool,
+    /// Cursor of the last fish in the page, to pass as `after`
+    end_cursor: Option<String>,
+}
+
+/// A change within a session
+#[derive(SimpleObject)]
+struct FishEvent {
+    /// Sequential ID of the event within its session
+    id: u64,
+    kind: FishEventKind,
+    /// The fish that changed. `null` for `EXPIRED`
+    fish: Option<FishObject>,
+}
+
+impl From<Event> for FishEvent {
+    fn from(event: Event) -> Self {
+        let (kind, fish) = match event.kind {
+            EventKind::Created(fish) => (FishEventKind::Created, Some(fish)),
+            EventKind::Updated(fish) => (FishEventKind::Updated, Some(fish)),
+            EventKind::Deleted(fish) => (FishEventKind::Deleted, Some(fish)),
+            EventKind::Expired => (FishEventKind::Expired, None),
+        };
+        Self {
+            id: event.id,
+            kind,
+            fish: fish.map(FishObject),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Enum)]
+enum FishEventKind {
+    Created,
+    Updated,
+    Deleted,
+    /// The session expired. This is always the last event
+    Expired,
+}
+
+/// Rejects queries with more than [MAX_ALIASES] aliased fields. Complexity
+/// doesn't catch a cheap field asked for over and over under different names,
+/// e.g. to look up many fish in one request.
+struct AliasLimit;
+
+impl ExtensionFactory for AliasLimit {
+    fn create(&self) -> Arc<dyn GraphQLExtension> {
+        Arc::new(Self)
+    }
+}
+
+#[async_trait::async_trait]
+impl GraphQLExtension for AliasLimit {
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        // Nesting depth is checked during parsing, so it's safe to recurse
+        let document = next.run(ctx, query, variables).await?;
+        let mut counter = AliasCounter {
+            document: &document,
+            fragments: HashMap::new(),
+        };
+        let aliases = document
+            .operations
+            .iter()
+            .map(|(_, operation)| {
+                counter.count(&operation.node.selection_set.node)
+            })
+            .fold(0, usize::saturating_add);
+        if aliases > MAX_ALIASES {
+            return Err(ServerError::new(
+                format!(
+                    "Query has {aliases} aliases, the limit is {MAX_ALIASES}"
+                ),
+                None,
+            ));
+        }
+        Ok(document)
+    }
+}
+
+/// Counts the aliases in a query, including in the fragments it spreads
+struct AliasCounter<'a> {
+    document: &'a ExecutableDocument,
+    /// Aliases in each fragment counted so far, so each is only walked once.
+    /// `None` while it's being counted, in case it spreads itself. That's
+    /// invalid and rejected later, so it can count as 0 here.
+    fragments: HashMap<&'a str, Option<usize>>,
+}
+
+impl<'a> AliasCounter<'a> {
+    fn count(&mut self, selection_set: &'a SelectionSet) -> usize {
+        selection_set
+            .items
+            .iter()
+            .map(|selection| match &selection.node {
+                Selection::Field(field) => {
+                    let nested = self.count(&field.node.selection_set.node);
+                    nested.saturating_add(field.node.alias.is_some().into())
+                }
+                Selection::FragmentSpread(spread) => {
+                    self.count_fragment(&spread.node.fragment_name.node)
+                }
+                Selection::InlineFragment(fragment) => {
+                    self.count(&fragment.node.selection_set.node)
+                }
+            })
+            .fold(0, usize::saturating_add)
+    }
+
+    fn count_fragment(&mut self, name: &'a str) -> usize {
+        if let Some(count) = self.fragments.get(name) {
+            return count.unwrap_or(0);
+        }
+        // Unknown fragments are also rejected later
+        let Some(fragment) = self.document.fragments.get(name) else {
+            return 0;
+        };
+        self.fragments.insert(name, None);
+        let count = self.count(&fragment.node.selection_set.node);
+        self.fragments.insert(name, Some(count));
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data::Store, scope::Scopes};
+    use async_graphql::Request;
+
+    async fn session() -> SessionStore {
+        Store::test(0).test_session(Scopes::all()).await
+    }
+
+    /// Run a query and return its error messages
+    async fn errors(query: &str) -> Vec<String> {
+        let request = Request::new(query).data(session().await);
+        let response = graphql_schema().execute(request).await;
+        response
+            .errors
+            .into_iter()
+            .map(|error| error.message)
+            .collect()
+    }
+
+    /// The biggest page, with every field, is within the limits
+    #[tokio::test]
+    async fn limits_allow_full_page() {
+        let query = "{ fishes(first: 1000) {
+            totalCount
+            pageInfo { hasNextPage endCursor }
+            edges {
+                cursor
+                node { id name species age weightKg version updatedAt }
+            }
+        } }";
+        assert_eq!(errors(query).await, Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn limit_depth() {
+        let of_type = |depth| {
+            "{ ofType ".repeat(depth) + "{ name }" + &" }".repeat(depth)
+        };
+        // As deep as GraphiQL's introspection query goes
+        let query = format!(
+            "{{ __schema {{ types {{ fields {{ type {} }} }} }} }}",
+            of_type(7)
+        );
+        assert_eq!(errors(&query).await, Vec::<String>::new());
+
+        let query = format!(
+            "{{ __schema {{ types {{ fields {{ type {} }} }} }} }}",
+            of_type(12)
+        );
+        assert_eq!(errors(&query).await, ["Query is nested too deep."]);
+    }
+
+    #[tokio::test]
+    async fn limit_complexity() {
+        let page = "fishes(first: 1000) {
+            totalCount
+            pageInfo { hasNextPage endCursor }
+            edges {
+                cursor
+                node { id name species age weightKg version updatedAt }
+            }
+        }";
+        let query = format!("{{ a: {page} b: {page} }}");
+        assert_eq!(errors(&query).await, ["Query is too complex."]);
+    }
+
+    /// Aliases are counted wherever they are, including each use of a
+    /// fragment
+    #[tokio::test]
+    async fn limit_aliases() {
+        let fields = |count| {
+            (0..count)
+                .map(|i| format!("f{i}: fish(id: 1) {{ id }}"))
+                .collect::<String>()
+        };
+        let query = format!("{{ {} }}", fields(MAX_ALIASES));
+        assert_eq!(errors(&query).await, Vec::<String>::new());
+
+        let too_many = format!(
+            "Query has {} aliases, the limit is {MAX_ALIASES}",
+            MAX_ALIASES + 1
+        );
+        let query = format!("{{ {} }}", fields(MAX_ALIASES + 1));
+        assert_eq!(errors(&query).await, [too_many.clone()]);
+        let query = format!(
+            "{{ fishes {{ edges {{ node {{ id {} }} }} }} }}",
+            (0..=MAX_ALIASES)
+                .map(|i| format!("n{i}: name "))
+                .collect::<String>()
+        );
+        assert_eq!(errors(&query).await, [too_many]);
+
+        let half = MAX_ALIASES / 2 + 1;
+        let query = format!(
+            "{{ ...F ... on QueryRoot {{ ...F }} }}
+            fragment F on QueryRoot {{ {} }}",
+            fields(half)
+        );
+        let expected = format!(
+            "Query has {} aliases, the limit is {MAX_ALIASES}",
+            half * 2
+        );
+        assert_eq!(errors(&query).await, [expected]);
+    }
+
+    #[tokio::test]
+    async fn limit_batch_size() {
+        let batch = |size| {
+            let requests = (0..size).map(|_| Request::new("{ __typename }"));
+            BatchRequest::Batch(requests.collect())
+        };
+        let Json(response) = graphql(
+            Extension(graphql_schema()),
+            session().await,
+            extract::Json(batch(MAX_BATCH_SIZE)),
+        )
+        .await
+        .unwrap();
+        assert!(response.is_ok());
+
+        let error = graphql(
+            Extension(graphql_schema()),
+            session().await,
+            extract::Json(batch(MAX_BATCH_SIZE + 1)),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error, Error::InvalidBody { .. }), "{error:?}");
+    }
+}
@@ -1,21 +1,46 @@
+use crate::{
+    Error,
+    compression::{Encoding, ReceivedEncoding},
+    extract,
+    routes::base_url,
+};
 use axum::{
-    Json,
+    Extension, Json,
+    body::Body,
     extract::{OriginalUri, Path, Query},
-    http::{HeaderMap, Method},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::{CookieJar, cookie::Cookie};
+use base64::{
+    Engine,
+    prelude::{BASE64_STANDARD_NO_PAD, BASE64_URL_SAFE_NO_PAD},
 };
 use bytes::Bytes;
+use futures::stream;
 use indexmap::{IndexMap, map::Entry};
-use serde::Serialize;
-use std::{mem, time::Duration};
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::IndexedRandom};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap, convert::Infallible, mem, ops::RangeInclusive,
+    time::Duration,
+};
 use tokio::time;
 
+/// Largest body that `/bytes`, `/stream-bytes`, and `/range` will generate
+const MAX_BYTES: usize = 1024 * 1024;
+/// Default chunk size for `/stream-bytes`
+const DEFAULT_CHUNK_SIZE: usize = 10 * 1024;
+
 /// A route to capture anything at /anything/*. Accepts any request and returns
-/// a JSON body detailing the request, similar to httpbin.
+/// a JSON body detailing the request, similar to httpbin. Compressed bodies are
+/// decoded before they get here, so the received encoding is reported too.
 pub async fn anything(
     uri: OriginalUri,
     method: Method,
     Query(params): Query<Vec<(String, String)>>,
     headers: HeaderMap,
+    encoding: Option<Extension<ReceivedEncoding>>,
     body: Bytes,
 ) -> Json<AnythingResponse> {
     let args = group_query_parameters(params);
@@ -46,9 +71,47 @@ pub async fn anything(
         headers,
         data,
         json,
+        encoding: encoding
+            .map(|Extension(ReceivedEncoding(encoding))| encoding),
     })
 }
 
+/// Respond with gzip-encoded details of the request, regardless of
+/// `Accept-Encoding`
+pub async fn gzip(
+    uri: OriginalUri,
+    method: Method,
+    query: Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> Response {
+    let response = anything(uri, method, query, headers, None, Bytes::new());
+    fixed_encoding(Encoding::Gzip, "gzipped", response.await)
+}
+
+/// Respond with deflate-encoded details of the request, regardless of
+/// `Accept-Encoding`
+pub async fn deflate(
+    uri: OriginalUri,
+    method: Method,
+    query: Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> Response {
+    let response = anything(uri, method, query, headers, None, Bytes::new());
+    fixed_encoding(Encoding::Deflate, "deflated", response.await)
+}
+
+/// Respond with brotli-encoded details of the request, regardless of
+/// `Accept-Encoding`
+pub async fn brotli(
+    uri: OriginalUri,
+    method: Method,
+    query: Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> Response {
+    let response = anything(uri, method, query, headers, None, Bytes::new());
+    fixed_encoding(Encoding::Brotli, "brotli", response.await)
+}
+
 /// Wait for n seconds before returning
 pub async fn delay(Path(duration): Path<u32>) -> crate::Result<()> {
     if duration > 30 {
@@ -58,6 +121,233 @@ pub async fn delay(Path(duration): Path<u32>) -> crate::Result<()> {
     Ok(())
 }
 
+/// Respond with a status code. `codes` is either a single code, or a
+/// comma-separated list to pick one from at random. Codes in a list can be
+/// weighted like `200:0.9,500:0.1`; the default weight is 1.
+pub async fn status(Path(codes): Path<String>) -> crate::Result<StatusCode> {
+    let choices = codes
+        .split(',')
+        .map(parse_weighted_status)
+        .collect::<crate::Result<Vec<_>>>()?;
+    let (status, _) = choices
+        .choose_weighted(&mut rand::rng(), |(_, weight)| *weight)
+        .map_err(|_| {
+            Error::InvalidPath(
+                "At least one code must have a positive weight".into(),
+            )
+        })?;
+    Ok(*status)
+}
+
+/// Set response headers from the query parameters, e.g.
+/// `?Cache-Control=no-cache`. Repeated parameters set the header multiple
+/// times. The body is the same headers as JSON.
+pub async fn response_headers(
+    Query(params): Query<Vec<(String, String)>>,
+) -> crate::Result<Response> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &params {
+        let name = HeaderName::try_from(name).map_err(|_| {
+            Error::InvalidQuery(format!("Invalid header name `{name}`"))
+        })?;
+        let value = HeaderValue::try_from(value).map_err(|_| {
+            Error::InvalidQuery(format!("Invalid value for header `{name}`"))
+        })?;
+        headers.append(name, value);
+    }
+
+    let mut response = Json(group_query_parameters(params)).into_response();
+    // Replace defaults like Content-Type, rather than adding to them
+    for name in headers.keys() {
+        response.headers_mut().remove(name);
+    }
+    response.headers_mut().extend(headers);
+    Ok(response)
+}
+
+/// Generate `n` random bytes. Pass `seed` to get the same bytes every time.
+pub async fn random_bytes(
+    Path(n): Path<usize>,
+    extract::Query(query): extract::Query<BytesQuery>,
+) -> crate::Result<Response> {
+    let data = generate_bytes(n, query.seed)?;
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], data)
+        .into_response())
+}
+
+/// Generate `n` random bytes, and send them in chunks with chunked transfer
+/// encoding instead of a `Content-Length`. Pass `seed` to get the same bytes
+/// every time, and `chunk_size` to set the size of each chunk.
+pub async fn stream_bytes(
+    Path(n): Path<usize>,
+    extract::Query(query): extract::Query<BytesQuery>,
+) -> crate::Result<Response> {
+    let chunk_size = query.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+    if chunk_size == 0 {
+        return Err(Error::InvalidQuery(
+            "`chunk_size` must be positive".into(),
+        ));
+    }
+    let data = Bytes::from(generate_bytes(n, query.seed)?);
+    let chunks: Vec<_> = (0..data.len())
+        .step_by(chunk_size)
+        .map(|start| {
+            Ok::<_, Infallible>(
+                data.slice(start..(start + chunk_size).min(data.len())),
+            )
+        })
+        .collect();
+    Ok((
+        [(header::CONTENT_TYPE, "application/octet-stream")],
+        Body::from_stream(stream::iter(chunks)),
+    )
+        .into_response())
+}
+
+/// Decode a base64 value and return it. Both the standard and URL-safe
+/// alphabets are accepted, with or without padding.
+pub async fn decode_base64(
+    Path(value): Path<String>,
+) -> crate::Result<Response> {
+    let trimmed = value.trim_end_matches('=');
+    let data = BASE64_URL_SAFE_NO_PAD
+        .decode(trimmed)
+        .or_else(|_| BASE64_STANDARD_NO_PAD.decode(trimmed))
+        .map_err(|_| Error::InvalidPath("Invalid base64 value".into()))?;
+    let content_type = if str::from_utf8(&data).is_ok() {
+        "text/plain; charset=utf-8"
+    } else {
+        "application/octet-stream"
+    };
+    Ok(([(header::CONTENT_TYPE, content_type)], data).into_response())
+}
+
+/// Return `n` bytes of the alphabet, repeated. A `Range` header with a single
+/// byte range gets a 206 with just those bytes. Other `Range` headers are
+/// ignored, and get the whole body.
+pub async fn range(
+    Path(n): Path<usize>,
+    headers: HeaderMap,
+) -> crate::Result<Response> {
+    check_size(n)?;
+    let data: Vec<u8> = (b'a'..=b'z').cycle().take(n).collect();
+    let accept_ranges =
+        (header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    let content_type = (
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+
+    let Some(range) = byte_range(&headers, n)? else {
+        return Ok(([accept_ranges, content_type], data).into_response());
+    };
+    let content_range = HeaderValue::from_str(&format!(
+        "bytes {}-{}/{n}",
+        range.start(),
+        range.end()
+    ))
+    .expect("Content-Range is ASCII");
+    Ok((
+        StatusCode::PARTIAL_CONTENT,
+        [
+            accept_ranges,
+            content_type,
+            (header::CONTENT_RANGE, content_range),
+        ],
+        data[range].to_vec(),
+    )
+        .into_response())
+}
+
+/// Redirect `n` times before landing on `/anything`, which echoes the final
+/// request. Redirects are relative, unless `absolute=true` is passed.
+pub async fn redirect(
+    Path(n): Path<u32>,
+    headers: HeaderMap,
+    extract::Query(query): extract::Query<RedirectQuery>,
+) -> crate::Result<Response> {
+    if query.absolute {
+        redirect_chain("absolute-redirect", n, query, Some(&headers))
+    } else {
+        redirect_chain("relative-redirect", n, query, None)
+    }
+}
+
+/// Redirect `n` times with relative `Location`s, then land on `/anything`
+pub async fn relative_redirect(
+    Path(n): Path<u32>,
+    extract::Query(query): extract::Query<RedirectQuery>,
+) -> crate::Result<Response> {
+    redirect_chain("relative-redirect", n, query, None)
+}
+
+/// Redirect `n` times with absolute `Location`s, then land on `/anything`
+pub async fn absolute_redirect(
+    Path(n): Path<u32>,
+    headers: HeaderMap,
+    extract::Query(query): extract::Query<RedirectQuery>,
+) -> crate::Result<Response> {
+    redirect_chain("absolute-redirect", n, query, Some(&headers))
+}
+
+/// Redirect to any URL, with any 3xx status code
+pub async fn redirect_to(
+    extract::Query(query): extract::Query<RedirectToQuery>,
+) -> crate::Result<Response> {
+    let status = redirect_status(query.status_code)?;
+    let location = HeaderValue::try_from(&query.url).map_err(|_| {
+        Error::InvalidQuery("`url` must be a valid header value".into())
+    })?;
+    Ok((status, [(header::LOCATION, location)]).into_response())
+}
+
+/// Redirect back to this same URL forever, to test a client's hop limit and
+/// loop detection
+pub async fn redirect_loop(
+    OriginalUri(uri): OriginalUri,
+    extract::Query(query): extract::Query<RedirectQuery>,
+) -> crate::Result<Response> {
+    let status = redirect_status(query.status_code)?;
+    let location = HeaderValue::from_str(&uri.to_string())
+        .expect("URI is a valid header value");
+    Ok((status, [(header::LOCATION, location)]).into_response())
+}
+
+/// Get the cookies sent with the request
+pub async fn cookies(jar: CookieJar) -> Json<CookiesResponse> {
+    let cookies = jar
+        .iter()
+        .map(|cookie| (cookie.name().to_owned(), cookie.value().to_owned()))
+        .collect();
+    Json(CookiesResponse { cookies })
+}
+
+/// Set a cookie for each query parameter, e.g. `?flavor=oatmeal`, then
+/// redirect to `/cookies`
+pub async fn set_cookies(
+    mut jar: CookieJar,
+    Query(params): Query<Vec<(String, String)>>,
+) -> crate::Result<(CookieJar, Response)> {
+    for (name, value) in params {
+        check_cookie_name(&name)?;
+        jar = jar.add(Cookie::build((name, value)).path("/"));
+    }
+    Ok((jar, cookies_redirect()))
+}
+
+/// Delete the cookie named by each query parameter, e.g. `?flavor`, then
+/// redirect to `/cookies`
+pub async fn delete_cookies(
+    mut jar: CookieJar,
+    Query(params): Query<Vec<(String, String)>>,
+) -> crate::Result<(CookieJar, Response)> {
+    for (name, _) in params {
+        check_cookie_name(&name)?;
+        jar = jar.remove(Cookie::build(name).path("/"));
+    }
+    Ok((jar, cookies_redirect()))
+}
+
 /// Details about the user's request
 #[derive(Debug, Serialize)]
 pub struct AnythingResponse {
@@ -73,6 +363,18 @@ pub struct AnythingResponse {
     data: String,
     /// JSON request body
     json: serde_json::Value,
+    /// `Content-Encoding` of the request body, which has already been decoded
+    /// in `data` and `json`
+    encoding: Option<String>,
+}
+
+/// An [AnythingResponse] with an extra flag field
+#[derive(Debug, Serialize)]
+struct FlaggedResponse<'a> {
+    #[serde(flatten)]
+    response: AnythingResponse,
+    #[serde(flatten)]
+    flag: BTreeMap<&'a str, bool>,
 }
 
 #[derive(Debug, Serialize)]
@@ -111,3 +413,239 @@ fn group_query_parameters(
             acc
         })
 }
+
+/// Response for `/cookies`
+#[derive(Debug, Serialize)]
+pub struct CookiesResponse {
+    /// Cookie values by name
+    cookies: BTreeMap<String, String>,
+}
+
+/// Query for `/bytes/{n}` and `/stream-bytes/{n}`
+#[derive(Debug, Deserialize)]
+pub struct BytesQuery {
+    /// Seed for the random generator
+    seed: Option<u64>,
+    /// Bytes per chunk, for `/stream-bytes/{n}`
+    chunk_size: Option<usize>,
+}
+
+/// Parse a status code with an optional weight, like `500:0.1`
+fn parse_weighted_status(choice: &str) -> crate::Result<(StatusCode, f64)> {
+    let (code, weight) = match choice.split_once(':') {
+        Some((code, weight)) => {
+            let weight = weight
+                .parse::<f64>()
+                .ok()
+                .filter(|weight| weight.is_finite() && *weight >= 0.0)
+                .ok_or_else(|| {
+                    Error::InvalidPath(format!(
+                        "Invalid weight `{weight}` for status code `{code}`"
+                    ))
+                })?;
+            (code, weight)
+        }
+        None => (choice, 1.0),
+    };
+    // 1xx codes aren't final responses, so they can't be sent here
+    let status = code
+        .parse::<u16>()
+        .ok()
+        .filter(|code| (200..600).contains(code))
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .ok_or_else(|| {
+            Error::InvalidPath(format!(
+                "Invalid status code `{code}`. Expected 200-599"
+            ))
+        })?;
+    Ok((status, weight))
+}
+
+/// Make sure a generated body isn't too big
+fn check_size(n: usize) -> crate::Result<()> {
+    if n > MAX_BYTES {
+        Err(Error::InvalidPath(format!(
+            "Size must be at most {MAX_BYTES} bytes"
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Generate `n` random bytes, from a seed if given
+fn generate_bytes(n: usize, seed: Option<u64>) -> crate::Result<Vec<u8>> {
+    check_size(n)?;
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_os_rng(),
+    };
+    let mut data = vec![0; n];
+    rng.fill(data.as_mut_slice());
+    Ok(data)
+}
+
+/// Get the byte range requested by the `Range` header (RFC 9110 section 14),
+/// for a body of `length` bytes. Return `None` if the whole body should be
+/// sent: there's no `Range` header, it's malformed, or it has multiple ranges,
+/// which aren't supported. Return an error if the range is entirely past the
+/// end of the body.
+fn byte_range(
+    headers: &HeaderMap,
+    length: usize,
+) -> crate::Result<Option<RangeInclusive<usize>>> {
+    let Some(range) = headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().strip_prefix("bytes="))
+        .filter(|range| !range.contains(','))
+    else {
+        return Ok(None);
+    };
+    let Some((start, end)) = range.split_once('-') else {
+        return Ok(None);
+    };
+    let unsatisfiable = Error::RangeNotSatisfiable {
+        length: length as u64,
+    };
+
+    if start.is_empty() {
+        // Suffix range: the last N bytes
+        let Ok(suffix) = end.parse::<usize>() else {
+            return Ok(None);
+        };
+        if suffix == 0 || length == 0 {
+            return Err(unsatisfiable);
+        }
+        return Ok(Some(length.saturating_sub(suffix)..=length - 1));
+    }
+
+    let Ok(start) = start.parse::<usize>() else {
+        return Ok(None);
+    };
+    let end = if end.is_empty() {
+        usize::MAX
+    } else {
+        match end.parse::<usize>() {
+            Ok(end) if end >= start => end,
+            _ => return Ok(None),
+        }
+    };
+    if start >= length {
+        return Err(unsatisfiable);
+    }
+    Ok(Some(start..=end.min(length - 1)))
+}
+
+/// Query for the redirect routes
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct RedirectQuery {
+    /// Status code for each redirect. Use 307 or 308 to test that the client
+    /// repeats the method and body on every hop
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status_code: Option<u16>,
+    /// For `/redirect/{n}`, use absolute `Location`s
+    #[serde(default, skip_serializing)]
+    absolute: bool,
+}
+
+/// Query for `/redirect-to`
+#[derive(Debug, Deserialize)]
+pub struct RedirectToQuery {
+    url: String,
+    status_code: Option<u16>,
+}
+
+/// Redirect to the next hop of a chain at `/{route}/{n - 1}`, or to
+/// `/anything` if this is the last hop. If `headers` is given, the `Location`
+/// is absolute. The query is passed along so every hop uses the same status.
+fn redirect_chain(
+    route: &str,
+    n: u32,
+    query: RedirectQuery,
+    headers: Option<&HeaderMap>,
+) -> crate::Result<Response> {
+    let status = redirect_status(query.status_code)?;
+    let path = match n {
+        0 => {
+            return Err(Error::InvalidPath(
+                "Number of redirects must be at least 1".into(),
+            ));
+        }
+        1 => "/anything".to_owned(),
+        n => {
+            let query = serde_urlencoded::to_string(&query)
+                .expect("Query is a flat struct");
+            let query = if query.is_empty() {
+                query
+            } else {
+                format!("?{query}")
+            };
+            format!("/{route}/{}{query}", n - 1)
+        }
+    };
+    let location = match headers {
+        Some(headers) => format!("{}{path}", base_url(headers)),
+        None => path,
+    };
+    let location =
+        HeaderValue::from_str(&location).expect("Location is a valid header");
+    Ok((status, [(header::LOCATION, location)]).into_response())
+}
+
+/// Cookie names can't be empty. Other characters are percent-encoded, so
+/// they're fine.
+fn check_cookie_name(name: &str) -> crate::Result<()> {
+    if name.is_empty() {
+        return Err(Error::InvalidQuery("Cookie name can't be empty".into()));
+    }
+    Ok(())
+}
+
+/// Send the client back to `/cookies` after changing its cookies, so it can
+/// see the result
+fn cookies_redirect() -> Response {
+    (
+        StatusCode::FOUND,
+        [(header::LOCATION, HeaderValue::from_static("/cookies"))],
+    )
+        .into_response()
+}
+
+/// Get the status code for a redirect. The default is 302, for compatibility
+/// with httpbin
+fn redirect_status(status_code: Option<u16>) -> crate::Result<StatusCode> {
+    let Some(code) = status_code else {
+        return Ok(StatusCode::FOUND);
+    };
+    StatusCode::from_u16(code)
+        .ok()
+        .filter(StatusCode::is_redirection)
+        .ok_or_else(|| {
+            Error::InvalidQuery(format!(
+                "Invalid redirect status code `{code}`. Expected 300-399"
+            ))
+        })
+}
+
+/// Encode an `/anything` response in a fixed coding, flagged like httpbin does
+/// (e.g. `"gzipped": true`). The compression layer leaves responses alone if
+/// they already have a `Content-Encoding`.
+fn fixed_encoding(
+    encoding: Encoding,
+    flag: &str,
+    Json(response): Json<AnythingResponse>,
+) -> Response {
+    let body = serde_json::to_vec(&FlaggedResponse {
+        response,
+        flag: BTreeMap::from([(flag, true)]),
+    })
+    .expect("Serialization is infallible");
+    (
+        [
+            (header::CONTENT_TYPE, "application/json"),
+            (header::CONTENT_ENCODING, encoding.name()),
+        ],
+        encoding.encode(&body),
+    )
+        .into_response()
+}
@@ -0,0 +1,565 @@
+//! A self-contained OAuth 2.0 authorization server (RFC 6749), for testing
+//! OAuth clients. Access tokens are session IDs, so they work anywhere a
+//! session does. There's no client registration: any client ID is accepted,
+//! and consent is granted automatically. Requested scopes are granted to the
+//! session; if none are requested, it gets every scope.
+
+use crate::{
+    data::{AuthorizationGrant, SessionId, Store},
+    extract::{Form, Query},
+    routes::base_url,
+    scope::{Scope, Scopes},
+};
+use axum::{
+    Extension, Json,
+    http::{HeaderMap, StatusCode, Uri, header},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use base64::{
+    Engine,
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Grant types supported by `POST /oauth/token`
+const GRANT_TYPES: &[&str] =
+    &["authorization_code", "client_credentials", "refresh_token"];
+/// PKCE methods (RFC 7636), in order of preference
+const CODE_CHALLENGE_METHODS: &[&str] = &["S256", "plain"];
+
+/// Authorization server metadata (RFC 8414)
+pub async fn oauth_metadata(headers: HeaderMap) -> Json<OAuthMetadata> {
+    let issuer = base_url(&headers);
+    Json(OAuthMetadata {
+        authorization_endpoint: format!("{issuer}/oauth/authorize"),
+        token_endpoint: format!("{issuer}/oauth/token"),
+        issuer,
+        response_types_supported: &["code"],
+        grant_types_supported: GRANT_TYPES,
+        code_challenge_methods_supported: CODE_CHALLENGE_METHODS,
+        token_endpoint_auth_methods_supported: &[
+            "none",
+            "client_secret_basic",
+            "client_secret_post",
+        ],
+        scopes_supported: Scope::ALL,
+    })
+}
+
+/// Start an authorization code grant. This renders a consent page that
+/// approves itself immediately, by submitting to `POST /oauth/authorize`.
+/// PKCE is required.
+pub async fn authorize_page(
+    Query(request): Query<AuthorizeRequest>,
+) -> crate::Result<Response> {
+    request.check_redirect_uri()?;
+    if let Err(error) = request.check() {
+        return Ok(request.redirect_error(error));
+    }
+    Ok(Html(request.consent_page()).into_response())
+}
+
+/// Approve an authorization request, and redirect back to the client with
+/// an authorization code
+pub async fn authorize(
+    Extension(store): Extension<Store>,
+    Form(request): Form<AuthorizeRequest>,
+) -> crate::Result<Response> {
+    request.check_redirect_uri()?;
+    let (code_challenge_method, scope) = match request.check() {
+        Ok((method, scope)) => (method.to_owned(), scope),
+        Err(error) => return Ok(request.redirect_error(error)),
+    };
+    let code = store
+        .create_authorization_code(AuthorizationGrant {
+            client_id: request.client_id.clone(),
+            redirect_uri: request.redirect_uri.clone(),
+            code_challenge: request.code_challenge.clone().unwrap_or_default(),
+            code_challenge_method,
+            scope,
+        })
+        .await?;
+    Ok(request.redirect(&AuthorizeResponse {
+        code: &code,
+        state: request.state.as_deref(),
+    }))
+}
+
+/// Exchange a grant for an access token
+pub async fn token(
+    Extension(store): Extension<Store>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, OAuthError> {
+    let request: TokenRequest =
+        serde_urlencoded::from_bytes(&body).map_err(|error| {
+            OAuthError::new(OAuthErrorCode::InvalidRequest, error.to_string())
+        })?;
+    let client = Client::from_request(&headers, &request)?;
+
+    let response = match request.grant_type.as_str() {
+        "authorization_code" => {
+            let code = request.require("code", &request.code)?;
+            let redirect_uri =
+                request.require("redirect_uri", &request.redirect_uri)?;
+            let code_verifier =
+                request.require("code_verifier", &request.code_verifier)?;
+
+            let grant = store
+                .redeem_authorization_code(code.to_owned())
+                .await?
+                .ok_or_else(|| {
+                    OAuthError::invalid_grant(
+                        "Authorization code is invalid or expired",
+                    )
+                })?;
+            if grant.client_id != client.id {
+                return Err(OAuthError::invalid_grant(
+                    "Authorization code was issued to another client",
+                ));
+            }
+            if grant.redirect_uri != redirect_uri {
+                return Err(OAuthError::invalid_grant(
+                    "`redirect_uri` doesn't match the authorization request",
+                ));
+            }
+            if !verify_pkce(&grant, code_verifier) {
+                return Err(OAuthError::invalid_grant(
+                    "`code_verifier` doesn't match the code challenge",
+                ));
+            }
+            issue_tokens(&store, client.id, grant.scope, true).await?
+        }
+        "client_credentials" => {
+            // Any secret is accepted, but it has to be there
+            if client.secret.is_none() {
+                return Err(OAuthError::new(
+                    OAuthErrorCode::InvalidClient,
+                    "Client credentials grant requires a client secret",
+                ));
+            }
+            let scope = parse_scope(request.scope.as_deref())?;
+            issue_tokens(&store, client.id, scope, false).await?
+        }
+        "refresh_token" => {
+            let refresh_token =
+                request.require("refresh_token", &request.refresh_token)?;
+            let grant = store
+                .redeem_refresh_token(refresh_token.to_owned())
+                .await?
+                .ok_or_else(|| {
+                    OAuthError::invalid_grant(
+                        "Refresh token is invalid, or its session has expired",
+                    )
+                })?;
+            if grant.client_id != client.id {
+                return Err(OAuthError::invalid_grant(
+                    "Refresh token was issued to another client",
+                ));
+            }
+            // The session lives on, so the access token stays the same
+            let info =
+                store.session(grant.session_id).refresh(None, None).await?;
+            let refresh_token = store
+                .create_refresh_token(info.id.clone(), client.id, info.scope)
+                .await?;
+            TokenResponse {
+                access_token: info.id,
+                token_type: "Bearer",
+                expires_in: info.remaining_seconds,
+                refresh_token: Some(refresh_token),
+                scope: info.scope,
+            }
+        }
+        grant_type => {
+            return Err(OAuthError::new(
+                OAuthErrorCode::UnsupportedGrantType,
+                format!(
+                    "Unsupported grant type `{grant_type}`. Expected one \
+                    of: {}",
+                    GRANT_TYPES.join(", ")
+                ),
+            ));
+        }
+    };
+
+    // Tokens must not be cached (RFC 6749 section 5.1)
+    Ok((
+        [
+            (header::CACHE_CONTROL, "no-store"),
+            (header::PRAGMA, "no-cache"),
+        ],
+        Json(response),
+    )
+        .into_response())
+}
+
+/// Create a new session, and issue its ID as an access token
+async fn issue_tokens(
+    store: &Store,
+    client_id: String,
+    scope: Scopes,
+    refresh: bool,
+) -> crate::Result<TokenResponse> {
+    let session = store.create_session(None, scope).await?;
+    let info = store.session(session.id.clone()).info().await?;
+    let refresh_token = if refresh {
+        Some(
+            store
+                .create_refresh_token(session.id.clone(), client_id, scope)
+                .await?,
+        )
+    } else {
+        None
+    };
+    Ok(TokenResponse {
+        access_token: session.id,
+        token_type: "Bearer",
+        expires_in: info.remaining_seconds,
+        refresh_token,
+        scope,
+    })
+}
+
+/// Parse the requested scopes. If none are requested, grant every scope
+fn parse_scope(scope: Option<&str>) -> Result<Scopes, OAuthError> {
+    match scope {
+        Some(scope) => scope.parse().map_err(|error| {
+            OAuthError::new(OAuthErrorCode::InvalidScope, error)
+        }),
+        None => Ok(Scopes::all()),
+    }
+}
+
+/// Check a PKCE code verifier against the challenge from the authorization
+/// request (RFC 7636 section 4.6)
+fn verify_pkce(grant: &AuthorizationGrant, code_verifier: &str) -> bool {
+    match grant.code_challenge_method.as_str() {
+        "S256" => {
+            BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier))
+                == grant.code_challenge
+        }
+        _ => code_verifier == grant.code_challenge,
+    }
+}
+
+/// Escape text for inclusion in HTML
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Response body for `GET /.well-known/oauth-authorization-server`
+#[derive(Debug, Serialize)]
+pub struct OAuthMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    response_types_supported: &'static [&'static str],
+    grant_types_supported: &'static [&'static str],
+    code_challenge_methods_supported: &'static [&'static str],
+    token_endpoint_auth_methods_supported: &'static [&'static str],
+    scopes_supported: &'static [Scope],
+}
+
+/// Query for `GET /oauth/authorize`, or form body for `POST /oauth/authorize`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AuthorizeRequest {
+    response_type: String,
+    client_id: String,
+    redirect_uri: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code_challenge: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code_challenge_method: Option<String>,
+}
+
+impl AuthorizeRequest {
+    /// Make sure the redirect URI is usable. If it isn't, errors can't be
+    /// sent back to the client, so they're shown to the user instead (RFC
+    /// 6749 section 4.1.2.1)
+    fn check_redirect_uri(&self) -> crate::Result<()> {
+        let valid = self
+            .redirect_uri
+            .parse::<Uri>()
+            .is_ok_and(|uri| uri.scheme().is_some());
+        if valid {
+            Ok(())
+        } else {
+            Err(crate::Error::InvalidQuery(
+                "`redirect_uri` must be an absolute URI".into(),
+            ))
+        }
+    }
+
+    /// Check the rest of the request, and return the PKCE method and the
+    /// requested scopes. Errors are sent back to the client via the redirect
+    /// URI.
+    fn check(&self) -> Result<(&str, Scopes), OAuthError> {
+        if self.response_type != "code" {
+            return Err(OAuthError::new(
+                OAuthErrorCode::UnsupportedResponseType,
+                "Only the `code` response type is supported",
+            ));
+        }
+        if self.code_challenge.is_none() {
+            return Err(OAuthError::new(
+                OAuthErrorCode::InvalidRequest,
+                "PKCE is required. Pass `code_challenge`",
+            ));
+        }
+        // Default is defined by RFC 7636
+        let method = self.code_challenge_method.as_deref().unwrap_or("plain");
+        if !CODE_CHALLENGE_METHODS.contains(&method) {
+            return Err(OAuthError::new(
+                OAuthErrorCode::InvalidRequest,
+                format!(
+                    "Unsupported `code_challenge_method`. Expected one of: {}",
+                    CODE_CHALLENGE_METHODS.join(", ")
+                ),
+            ));
+        }
+        let scope = parse_scope(self.scope.as_deref())?;
+        Ok((method, scope))
+    }
+
+    /// Redirect back to the client, with the given query params
+    fn redirect(&self, params: &impl Serialize) -> Response {
+        let separator = if self.redirect_uri.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        let query = serde_urlencoded::to_string(params)
+            .expect("Redirect params are a flat struct");
+        Redirect::to(&format!("{}{separator}{query}", self.redirect_uri))
+            .into_response()
+    }
+
+    /// Redirect back to the client with an error
+    fn redirect_error(&self, error: OAuthError) -> Response {
+        self.redirect(&AuthorizeErrorResponse {
+            error: error.error,
+            error_description: &error.error_description,
+            state: self.state.as_deref(),
+        })
+    }
+
+    /// Render a page that approves the request automatically. The button is
+    /// a fallback for browsers without JavaScript.
+    fn consent_page(&self) -> String {
+        let value =
+            serde_json::to_value(self).expect("Serialization is infallible");
+        let inputs: String = value
+            .as_object()
+            .expect("Request is an object")
+            .iter()
+            .filter_map(|(name, value)| {
+                Some(format!(
+                    r#"<input type="hidden" name="{}" value="{}">"#,
+                    escape_html(name),
+                    escape_html(value.as_str()?)
+                ))
+            })
+            .collect();
+        let scope = self
+            .scope
+            .as_deref()
+            .map(|scope| {
+                format!(" with scope <code>{}</code>", escape_html(scope))
+            })
+            .unwrap_or_default();
+        format!(
+            r#"<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset="utf-8">
+    <title>Authorize - Shoal</title>
+  </head>
+  <body>
+    <form method="post" action="/oauth/authorize">
+      <p><strong>{client_id}</strong> is requesting access to a new session{scope}.</p>
+      {inputs}
+      <button type="submit">Approve</button>
+    </form>
+    <script>document.forms[0].submit();</script>
+  </body>
+</html>
+"#,
+            client_id = escape_html(&self.client_id),
+        )
+    }
+}
+
+/// Query params for a successful redirect from `POST /oauth/authorize`
+#[derive(Debug, Serialize)]
+struct AuthorizeResponse<'a> {
+    code: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<&'a str>,
+}
+
+/// Query params for an error redirect from `/oauth/authorize`
+#[derive(Debug, Serialize)]
+struct AuthorizeErrorResponse<'a> {
+    error: OAuthErrorCode,
+    error_description: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<&'a str>,
+}
+
+/// Form body for `POST /oauth/token`. Which fields are required depends on
+/// the grant type.
+#[derive(Debug, Deserialize)]
+struct TokenRequest {
+    grant_type: String,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    scope: Option<String>,
+}
+
+impl TokenRequest {
+    /// Get a field that's required for the grant type
+    fn require<'a>(
+        &self,
+        name: &str,
+        value: &'a Option<String>,
+    ) -> Result<&'a str, OAuthError> {
+        value.as_deref().ok_or_else(|| {
+            OAuthError::new(
+                OAuthErrorCode::InvalidRequest,
+                format!("`{name}` is required for `{}`", self.grant_type),
+            )
+        })
+    }
+}
+
+/// Response body for `POST /oauth/token`
+#[derive(Debug, Serialize)]
+struct TokenResponse {
+    access_token: SessionId,
+    token_type: &'static str,
+    expires_in: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    scope: Scopes,
+}
+
+/// The client making a token request
+struct Client {
+    id: String,
+    secret: Option<String>,
+}
+
+impl Client {
+    /// Get the client's credentials from either HTTP Basic auth or the body
+    /// (RFC 6749 section 2.3.1). A client ID is always required.
+    fn from_request(
+        headers: &HeaderMap,
+        request: &TokenRequest,
+    ) -> Result<Self, OAuthError> {
+        let basic = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|credentials| BASE64_STANDARD.decode(credentials).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        if let Some(credentials) = basic {
+            let (id, secret) =
+                credentials.split_once(':').unwrap_or((&credentials, ""));
+            return Ok(Self {
+                id: id.to_owned(),
+                secret: Some(secret.to_owned()),
+            });
+        }
+
+        let id = request.client_id.clone().ok_or_else(|| {
+            OAuthError::new(
+                OAuthErrorCode::InvalidClient,
+                "Client authentication is required. Pass `client_id`",
+            )
+        })?;
+        Ok(Self {
+            id,
+            secret: request.client_secret.clone(),
+        })
+    }
+}
+
+/// An error response from the OAuth endpoints. These use the format from RFC
+/// 6749 rather than our usual error format, because that's what OAuth
+/// clients expect.
+#[derive(Debug, Serialize)]
+pub struct OAuthError {
+    error: OAuthErrorCode,
+    error_description: String,
+}
+
+impl OAuthError {
+    fn new(error: OAuthErrorCode, description: impl Into<String>) -> Self {
+        Self {
+            error,
+            error_description: description.into(),
+        }
+    }
+
+    fn invalid_grant(description: &str) -> Self {
+        Self::new(OAuthErrorCode::InvalidGrant, description)
+    }
+}
+
+impl From<crate::Error> for OAuthError {
+    fn from(error: crate::Error) -> Self {
+        error.log();
+        let code = if error.status().is_server_error() {
+            OAuthErrorCode::ServerError
+        } else {
+            OAuthErrorCode::InvalidRequest
+        };
+        Self::new(code, error.detail())
+    }
+}
+
+impl IntoResponse for OAuthError {
+    fn into_response(self) -> Response {
+        let status = match self.error {
+            OAuthErrorCode::InvalidClient => StatusCode::UNAUTHORIZED,
+            OAuthErrorCode::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        let mut response = (status, Json(self)).into_response();
+        if status == StatusCode::UNAUTHORIZED {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                header::HeaderValue::from_static("Basic realm=\"shoal\""),
+            );
+        }
+        response
+    }
+}
+
+/// Error codes from RFC 6749 section 5.2
+#[derive(Copy, Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum OAuthErrorCode {
+    InvalidRequest,
+    InvalidClient,
+    InvalidGrant,
+    InvalidScope,
+    UnsupportedGrantType,
+    UnsupportedResponseType,
+    ServerError,
+}
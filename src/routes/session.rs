@@ -0,0 +1,257 @@
+//! Session routes
+
+use crate::{
+    Error,
+    auth::SESSION_COOKIE,
+    data::{
+        Fish, Image, MAX_SESSION_IMAGES, SessionId, SessionInfo, SessionStore,
+        Store,
+    },
+    format::Format,
+    jwt::{self, Claims, Keys},
+    routes::{CreateFishRequest, MAX_IMAGE_SIZE},
+    scope::{Scope, Scopes},
+    validate::{Validate, Validator},
+};
+use axum::{
+    Extension, Json,
+    http::{HeaderMap, StatusCode},
+};
+use axum_extra::extract::{
+    CookieJar,
+    cookie::{Cookie, SameSite},
+};
+use bytes::Bytes;
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+
+/// Create new temporary session. The body can optionally specify the
+/// session's lifetime and scopes, and request a signed session token or a
+/// session cookie.
+pub async fn login(
+    Extension(store): Extension<Store>,
+    Extension(keys): Extension<Keys>,
+    jar: CookieJar,
+    body: Bytes,
+) -> crate::Result<(CookieJar, Json<LoginResponse>)> {
+    let body = SessionRequest::parse(&body)?;
+    let mut response = store
+        .create_session(body.ttl_seconds, body.scope.unwrap_or(Scopes::all()))
+        .await?;
+    response.token =
+        body.sign(&keys, &response.id, response.expires_at, response.scope);
+    let jar = body.set_cookie(jar, &response.id, response.expires_at);
+    Ok((jar, Json(response)))
+}
+
+/// Delete the current session, including all its fish. If the request has
+/// a session cookie, it's cleared.
+pub async fn logout(
+    store: SessionStore,
+    jar: CookieJar,
+) -> crate::Result<(CookieJar, StatusCode)> {
+    store.logout().await?;
+    let jar = if jar.get(SESSION_COOKIE).is_some() {
+        jar.remove(session_cookie(String::new(), time::Duration::ZERO))
+    } else {
+        jar
+    };
+    Ok((jar, StatusCode::NO_CONTENT))
+}
+
+/// Get metadata for the current session
+pub async fn get_session(
+    store: SessionStore,
+) -> crate::Result<Json<SessionInfo>> {
+    store.info().await.map(Json)
+}
+
+/// Extend the current session's expiration. The body can optionally specify
+/// a new lifetime, narrow the session's scopes, and request a new session
+/// token or cookie with the new expiration.
+pub async fn refresh_session(
+    store: SessionStore,
+    Extension(keys): Extension<Keys>,
+    jar: CookieJar,
+    body: Bytes,
+) -> crate::Result<(CookieJar, Json<SessionInfo>)> {
+    let body = SessionRequest::parse(&body)?;
+    let mut info = store.refresh(body.ttl_seconds, body.scope).await?;
+    info.token = body.sign(&keys, &info.id, info.expires_at, info.scope);
+    let jar = body.set_cookie(jar, &info.id, info.expires_at);
+    Ok((jar, Json(info)))
+}
+
+/// Copy the current session, including all its fish, into a new session.
+/// The body can optionally specify the new session's lifetime and scopes; by
+/// default it has the same lifetime and scopes as the current session.
+pub async fn clone_session(
+    store: SessionStore,
+    Extension(keys): Extension<Keys>,
+    jar: CookieJar,
+    body: Bytes,
+) -> crate::Result<(CookieJar, Json<LoginResponse>)> {
+    store.authorize(Scope::FishRead)?;
+    let body = SessionRequest::parse(&body)?;
+    let mut response = store.fork(body.ttl_seconds, body.scope).await?;
+    response.token =
+        body.sign(&keys, &response.id, response.expires_at, response.scope);
+    let jar = body.set_cookie(jar, &response.id, response.expires_at);
+    Ok((jar, Json(response)))
+}
+
+/// Public keys for verifying session tokens (RFC 7517)
+pub async fn jwks(Extension(keys): Extension<Keys>) -> Json<jwt::Jwks> {
+    Json(keys.jwks())
+}
+
+/// Export all fish in the current session, so they can be loaded into
+/// another session with `POST /session/restore`
+pub async fn get_snapshot(
+    store: SessionStore,
+) -> crate::Result<Json<SessionSnapshot>> {
+    store.authorize(Scope::FishRead)?;
+    store.snapshot().await.map(Json)
+}
+
+/// Largest snapshot body that can be restored, in bytes. Images are inlined as
+/// base64, so a session's images can take up to 4/3 of their size, plus room
+/// for the fish themselves.
+pub const MAX_SNAPSHOT_SIZE: usize =
+    MAX_SESSION_IMAGES * MAX_IMAGE_SIZE.div_ceil(3) * 4 + 32 * 1024 * 1024;
+
+/// Replace all fish in the current session with a snapshot from
+/// `GET /session/snapshot`. Restored fish get new IDs. This replaces the
+/// existing fish, so it requires permission to delete them.
+pub async fn restore_snapshot(
+    store: SessionStore,
+    headers: HeaderMap,
+    body: Bytes,
+) -> crate::Result<Json<Vec<Fish>>> {
+    store.authorize(Scope::FishWrite)?;
+    store.authorize(Scope::FishDelete)?;
+    if Format::from_content_type(&headers) != Some(Format::Json) {
+        return Err(Error::UnsupportedMediaType {
+            accepted: vec![Format::Json.media_type()],
+        });
+    }
+    // Every image is decoded to validate it, which is CPU-heavy, so keep it
+    // off the async runtime
+    let body: SessionSnapshot =
+        tokio::task::spawn_blocking(move || Format::Json.decode(&body))
+            .await
+            .expect("Snapshot decoding panicked")?;
+    body.validate()?;
+    store.restore(body).await.map(Json)
+}
+
+/// Optional request body for `POST /login`, `POST /session/refresh`, and
+/// `POST /session/clone`
+#[derive(Debug, Default, Deserialize)]
+pub struct SessionRequest {
+    /// Session lifetime, in seconds. Defaults to the server's configured
+    /// lifetime for new sessions, or the session's existing lifetime for
+    /// refreshes
+    pub ttl_seconds: Option<u64>,
+    /// If given, also issue a session token (JWT) signed with this algorithm
+    pub jwt: Option<jwt::Algorithm>,
+    /// Scopes to grant the session. New sessions get every scope by default.
+    /// Refreshed and cloned sessions can only narrow their scopes.
+    pub scope: Option<Scopes>,
+    /// Also set the `shoal_session` cookie, so browsers can authenticate
+    /// without an `Authorization` header
+    #[serde(default)]
+    pub cookie: bool,
+}
+
+impl SessionRequest {
+    /// Parse the request body. An empty body gets the defaults
+    fn parse(body: &[u8]) -> crate::Result<Self> {
+        if body.is_empty() {
+            Ok(Self::default())
+        } else {
+            let Json(body) = Json::from_bytes(body)?;
+            Ok(body)
+        }
+    }
+
+    /// Sign a session token for the session, if one was requested
+    fn sign(
+        &self,
+        keys: &Keys,
+        session_id: &SessionId,
+        expires_at: Timestamp,
+        scope: Scopes,
+    ) -> Option<String> {
+        let algorithm = self.jwt?;
+        let claims = Claims::new(session_id.clone(), expires_at, scope);
+        Some(keys.sign(algorithm, &claims))
+    }
+
+    /// Set the session cookie, if one was requested. It expires along with
+    /// the session.
+    fn set_cookie(
+        &self,
+        jar: CookieJar,
+        session_id: &SessionId,
+        expires_at: Timestamp,
+    ) -> CookieJar {
+        if !self.cookie {
+            return jar;
+        }
+        let max_age = expires_at.duration_since(Timestamp::now()).as_secs();
+        jar.add(session_cookie(
+            session_id.to_string(),
+            time::Duration::seconds(max_age.max(0)),
+        ))
+    }
+}
+
+/// Build the session cookie. It's hidden from scripts, and only sent on
+/// same-site requests and top-level navigations to protect against CSRF.
+fn session_cookie(value: String, max_age: time::Duration) -> Cookie<'static> {
+    Cookie::build((SESSION_COOKIE, value))
+        .path("/")
+        .max_age(max_age)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .build()
+}
+
+/// Response body for `POST /login` and `POST /session/clone`
+#[derive(Debug, Serialize)]
+pub struct LoginResponse {
+    pub id: SessionId,
+    pub expires_at: Timestamp,
+    /// Scopes granted to the session
+    pub scope: Scopes,
+    /// Signed session token, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/// All fish in a session, for `GET /session/snapshot` and
+/// `POST /session/restore`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SessionSnapshot {
+    pub fish: Vec<SnapshotFish>,
+}
+
+impl Validate for SessionSnapshot {
+    fn check(&self, validator: &mut Validator) {
+        for (index, fish) in self.fish.iter().enumerate() {
+            validator.ensure_item("fish", index, &fish.fish);
+        }
+    }
+}
+
+/// A fish in a [SessionSnapshot]. IDs aren't included, because restored fish
+/// get new ones.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SnapshotFish {
+    #[serde(flatten)]
+    pub fish: CreateFishRequest,
+    /// Image for the fish, as base64
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<Image>,
+}
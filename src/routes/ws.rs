@@ -0,0 +1,233 @@
+//! WebSocket routes
+
+use crate::{
+    conditional::Conditions,
+    data::{FishId, SessionStore},
+    events::{Event, EventKind},
+    routes::{CreateFishRequest, ListFishQuery, UpdateFishRequest},
+    scope::Scope,
+    validate::{FieldError, Validate},
+};
+use axum::{
+    extract::ws::{
+        CloseFrame, Message, WebSocket, WebSocketUpgrade, close_code,
+    },
+    response::Response,
+};
+use futures::{SinkExt, StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+/// Interact with the fish tank over a WebSocket. Each text (or binary) frame
+/// from the client is a JSON [Command], and the server replies to each with
+/// a [ServerMessage::Response] or [ServerMessage::Error]. If the connection
+/// is authenticated with a session, every change to the session's fish is also
+/// pushed as a [ServerMessage::Event], including changes made by other
+/// clients. The socket is closed when the session expires. Each command
+/// requires the same scope as its HTTP equivalent.
+pub async fn websocket(
+    store: SessionStore,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade.on_upgrade(|socket| handle_websocket(socket, store))
+}
+
+/// Echo every text and binary frame back to the client, similar to
+/// `/anything`. Close frames are answered with the same code and reason. Pings
+/// are answered automatically.
+pub async fn websocket_echo(upgrade: WebSocketUpgrade) -> Response {
+    upgrade.on_upgrade(handle_echo)
+}
+
+async fn handle_websocket(mut socket: WebSocket, store: SessionStore) {
+    // Anonymous sessions can't change, so there's nothing to listen for.
+    // Sessions that can't read fish don't get to see changes either
+    let subscription = store
+        .authorize(Scope::FishRead)
+        .and_then(|()| store.subscribe(None));
+    let mut events = match subscription {
+        Ok(events) => events.boxed(),
+        Err(_) => stream::pending().boxed(),
+    };
+
+    loop {
+        let message = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    handle_command(&store, text.as_bytes()).await
+                }
+                Some(Ok(Message::Binary(bytes))) => {
+                    handle_command(&store, &bytes).await
+                }
+                // Pings are answered automatically
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                // Flush the automatic close reply
+                Some(Ok(Message::Close(_))) => {
+                    let _ = socket.flush().await;
+                    break;
+                }
+                None => break,
+                Some(Err(error)) => {
+                    debug!(%error, "WebSocket error");
+                    break;
+                }
+            },
+            Some(event) = events.next() => {
+                if let EventKind::Expired = event.kind {
+                    let _ = send(&mut socket, ServerMessage::Event { event }).await;
+                    let _ = socket
+                        .send(Message::Close(Some(CloseFrame {
+                            code: close_code::AWAY,
+                            reason: "Session expired".into(),
+                        })))
+                        .await;
+                    break;
+                }
+                ServerMessage::Event { event }
+            }
+        };
+        if send(&mut socket, message).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Execute a single command from the client
+async fn handle_command(store: &SessionStore, bytes: &[u8]) -> ServerMessage {
+    let command: Command = match serde_json::from_slice(bytes) {
+        Ok(command) => command,
+        Err(error) => {
+            return ServerMessage::Error {
+                request_id: None,
+                status: 400,
+                detail: format!("Invalid command: {error}"),
+                errors: Vec::new(),
+            };
+        }
+    };
+
+    match run_command(store, command.kind).await {
+        Ok(data) => ServerMessage::Response {
+            request_id: command.request_id,
+            data,
+        },
+        Err(error) => {
+            error.log();
+            ServerMessage::Error {
+                request_id: command.request_id,
+                status: error.status().as_u16(),
+                detail: error.detail(),
+                errors: error.field_errors(),
+            }
+        }
+    }
+}
+
+async fn run_command(
+    store: &SessionStore,
+    command: CommandKind,
+) -> crate::Result<serde_json::Value> {
+    let data = match command {
+        CommandKind::List => {
+            store.authorize(Scope::FishRead)?;
+            to_json(store.list(&ListFishQuery::default()).await?.items)
+        }
+        CommandKind::Get { id } => {
+            store.authorize(Scope::FishRead)?;
+            to_json(store.get(id).await?)
+        }
+        CommandKind::Create { fish } => {
+            store.authorize(Scope::FishWrite)?;
+            fish.validate()?;
+            to_json(store.create(fish).await?)
+        }
+        CommandKind::Update { id, fish } => {
+            store.authorize(Scope::FishWrite)?;
+            fish.validate()?;
+            to_json(store.update(id, fish, &Conditions::default()).await?)
+        }
+        CommandKind::Delete { id } => {
+            store.authorize(Scope::FishDelete)?;
+            to_json(store.delete(id, &Conditions::default()).await?)
+        }
+    };
+    Ok(data)
+}
+
+fn to_json(value: impl Serialize) -> serde_json::Value {
+    serde_json::to_value(value).expect("Serialization is infallible")
+}
+
+async fn send(
+    socket: &mut WebSocket,
+    message: ServerMessage,
+) -> Result<(), axum::Error> {
+    let json =
+        serde_json::to_string(&message).expect("Serialization is infallible");
+    socket.send(Message::Text(json.into())).await
+}
+
+async fn handle_echo(mut socket: WebSocket) {
+    while let Some(Ok(message)) = socket.recv().await {
+        let reply = match message {
+            Message::Text(_) | Message::Binary(_) => message,
+            // Pings are answered automatically
+            Message::Ping(_) | Message::Pong(_) => continue,
+            // The close reply, with the same code and reason, is queued
+            // automatically. We just need to flush it out
+            Message::Close(_) => {
+                let _ = socket.flush().await;
+                break;
+            }
+        };
+        if socket.send(reply).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// A command sent by the client over `/ws`
+#[derive(Debug, Deserialize)]
+struct Command {
+    /// Optional client-generated ID, which will be included in the response
+    /// so the client can match them up
+    #[serde(default)]
+    request_id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    kind: CommandKind,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum CommandKind {
+    List,
+    Get { id: FishId },
+    Create { fish: CreateFishRequest },
+    Update { id: FishId, fish: UpdateFishRequest },
+    Delete { id: FishId },
+}
+
+/// A message sent by the server over `/ws`
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    /// Successful result of a command
+    Response {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<serde_json::Value>,
+        data: serde_json::Value,
+    },
+    /// A command failed
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<serde_json::Value>,
+        /// Equivalent HTTP status code
+        status: u16,
+        detail: String,
+        /// Invalid fields in the command, if applicable
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        errors: Vec<FieldError>,
+    },
+    /// A fish changed in this session
+    Event { event: Event },
+}
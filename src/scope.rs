@@ -0,0 +1,171 @@
+//! Permission scopes for sessions. A session is granted a set of scopes when
+//! it's created, and each fish operation requires one of them. Requests
+//! without a session can only read the default fish.
+
+use rusqlite::types::{
+    FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+/// A single permission
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[expect(
+    clippy::enum_variant_names,
+    reason = "Variants match the scope names, which are prefixed by resource"
+)]
+pub enum Scope {
+    /// List and get fish, their images, and their events
+    FishRead,
+    /// Create and modify fish
+    FishWrite,
+    /// Delete fish
+    FishDelete,
+}
+
+impl Scope {
+    pub const ALL: &[Self] =
+        &[Self::FishRead, Self::FishWrite, Self::FishDelete];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::FishRead => "fish:read",
+            Self::FishWrite => "fish:write",
+            Self::FishDelete => "fish:delete",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl Serialize for Scope {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+/// A set of scopes. The string form is space-separated, as in OAuth (RFC 6749
+/// section 3.3).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Scopes(u8);
+
+impl Scopes {
+    /// Every scope. Sessions get this if they don't request anything
+    pub fn all() -> Self {
+        Self::from_iter(Scope::ALL.iter().copied())
+    }
+
+    /// Scopes for requests without a session
+    pub fn anonymous() -> Self {
+        Self::from_iter([Scope::FishRead])
+    }
+
+    pub fn contains(self, scope: Scope) -> bool {
+        self.0 & scope.bit() != 0
+    }
+
+    /// Scopes in both `self` and `other`
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Get the first scope in `self` that isn't in `other`, if any
+    pub fn excess(self, other: Self) -> Option<Scope> {
+        self.iter().find(|scope| !other.contains(*scope))
+    }
+
+    fn iter(self) -> impl Iterator<Item = Scope> {
+        Scope::ALL
+            .iter()
+            .copied()
+            .filter(move |scope| self.contains(*scope))
+    }
+}
+
+impl FromIterator<Scope> for Scopes {
+    fn from_iter<T: IntoIterator<Item = Scope>>(iter: T) -> Self {
+        Self(iter.into_iter().fold(0, |bits, scope| bits | scope.bit()))
+    }
+}
+
+impl Display for Scopes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, scope) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{scope}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Scopes {
+    type Err = String;
+
+    /// Parse space-separated scopes. Return an error message naming the
+    /// first unknown scope.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split_whitespace()
+            .map(|name| {
+                Scope::ALL
+                    .iter()
+                    .copied()
+                    .find(|scope| scope.name() == name)
+                    .ok_or_else(|| {
+                        format!(
+                            "Unknown scope `{name}`. Expected any of: {}",
+                            Self::all()
+                        )
+                    })
+            })
+            .collect()
+    }
+}
+
+impl Serialize for Scopes {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Scopes {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+impl ToSql for Scopes {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.to_string().into())
+    }
+}
+
+impl FromSql for Scopes {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|error: String| FromSqlError::Other(error.into()))
+    }
+}
@@ -0,0 +1,206 @@
+//! Validation of request bodies, beyond what deserialization can enforce
+
+use crate::Error;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_path_to_error::{Path, Segment};
+
+/// A request body type with constraints on its field values
+pub trait Validate {
+    /// Check each field, recording any problems in the validator
+    fn check(&self, validator: &mut Validator);
+
+    /// Check all fields. If any are invalid, return an error listing every
+    /// problem, so the client can fix them all in one go.
+    fn validate(&self) -> crate::Result<()> {
+        let mut validator = Validator::default();
+        self.check(&mut validator);
+        validator.finish()
+    }
+}
+
+/// Accumulator for field errors
+#[derive(Debug, Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    /// Record an error for a top-level field if the condition is false
+    pub fn ensure(&mut self, field: &str, valid: bool, reason: &str) {
+        if !valid {
+            self.errors.push(FieldError {
+                field: field.to_owned(),
+                pointer: format!("/{}", escape_pointer(field)),
+                reason: reason.to_owned(),
+            });
+        }
+    }
+
+    /// Check one element of a list field, recording its errors under the
+    /// element's path, e.g. `items[0].name`
+    pub fn ensure_item(
+        &mut self,
+        field: &str,
+        index: usize,
+        item: &impl Validate,
+    ) {
+        let mut inner = Self::default();
+        item.check(&mut inner);
+        self.errors
+            .extend(inner.errors.into_iter().map(|error| FieldError {
+                field: format!("{field}[{index}].{}", error.field),
+                pointer: format!(
+                    "/{}/{index}{}",
+                    escape_pointer(field),
+                    error.pointer
+                ),
+                reason: error.reason,
+            }));
+    }
+
+    /// Check a nested object field, recording its errors under the field's
+    /// path, e.g. `fish.name`
+    pub fn ensure_field(&mut self, field: &str, item: &impl Validate) {
+        let mut inner = Self::default();
+        item.check(&mut inner);
+        self.errors
+            .extend(inner.errors.into_iter().map(|error| FieldError {
+                field: format!("{field}.{}", error.field),
+                pointer: format!("/{}{}", escape_pointer(field), error.pointer),
+                reason: error.reason,
+            }));
+    }
+
+    /// Return an error if any fields were invalid
+    pub fn finish(self) -> crate::Result<()> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Validation(self.errors))
+        }
+    }
+}
+
+/// A single invalid field in a request body
+#[derive(Clone, Debug, Serialize)]
+pub struct FieldError {
+    /// Path to the field, e.g. `name` or `items[0].name`
+    pub field: String,
+    /// JSON Pointer (RFC 6901) to the field, e.g. `/items/0/name`
+    pub pointer: String,
+    /// What's wrong with the field
+    pub reason: String,
+}
+
+impl FieldError {
+    /// Build a field error from a deserialization failure
+    pub fn from_serde(
+        error: &serde_path_to_error::Error<serde_json::Error>,
+    ) -> Self {
+        let path = error.path();
+        let inner = error.inner();
+        let mut reason = inner.to_string();
+        // serde_json appends the position to the message. The pointer is more
+        // useful than the position, so drop it
+        if inner.line() > 0
+            && let Some((message, _)) = reason.rsplit_once(" at line ")
+        {
+            reason = message.to_owned();
+        }
+
+        // For missing fields, the path points to the parent object. Point to
+        // the missing field instead
+        let missing = reason
+            .strip_prefix("missing field `")
+            .and_then(|rest| rest.split_once('`'))
+            .map(|(field, _)| field.to_owned());
+        if let Some(missing) = missing {
+            let field = if path.iter().len() == 0 {
+                missing.clone()
+            } else {
+                format!("{path}.{missing}")
+            };
+            Self {
+                field,
+                pointer: format!(
+                    "{}/{}",
+                    pointer(path),
+                    escape_pointer(&missing)
+                ),
+                reason: "missing field".to_owned(),
+            }
+        } else {
+            Self {
+                field: path.to_string(),
+                pointer: pointer(path),
+                reason,
+            }
+        }
+    }
+}
+
+/// Deserialize a JSON value, tracking the path to any invalid field
+pub fn from_value<T: DeserializeOwned>(
+    value: serde_json::Value,
+) -> Result<T, serde_path_to_error::Error<serde_json::Error>> {
+    serde_path_to_error::deserialize(value)
+}
+
+/// Convert a serde path to a JSON Pointer
+fn pointer(path: &Path) -> String {
+    path.iter()
+        .filter_map(|segment| match segment {
+            Segment::Seq { index } => Some(index.to_string()),
+            Segment::Map { key } => Some(escape_pointer(key)),
+            Segment::Enum { .. } | Segment::Unknown => None,
+        })
+        .map(|token| format!("/{token}"))
+        .collect()
+}
+
+/// Escape a reference token for a JSON Pointer
+fn escape_pointer(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize)]
+    #[expect(dead_code)]
+    struct Outer {
+        items: Vec<Inner>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[expect(dead_code)]
+    struct Inner {
+        name: String,
+    }
+
+    /// Missing fields are found by parsing serde's error message, so this
+    /// fails if the wording changes
+    #[test]
+    fn missing_field() {
+        let error = |value| {
+            let error = from_value::<Outer>(value).unwrap_err();
+            let error = FieldError::from_serde(&error);
+            (error.field, error.pointer, error.reason)
+        };
+        assert_eq!(
+            error(json!({})),
+            ("items".into(), "/items".into(), "missing field".into())
+        );
+        assert_eq!(
+            error(json!({"items": [{"name": "Barry"}, {}]})),
+            (
+                "items[1].name".into(),
+                "/items/1/name".into(),
+                "missing field".into()
+            )
+        );
+    }
+}